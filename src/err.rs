@@ -8,6 +8,12 @@ pub enum HdtrError {
     NoInputFilesSpecified,
     InputFileDoesNotExist(String),
     InputFileReadError(ImageError),
+    /// An image couldn't be fetched from a URL.
+    Download(Box<ureq::Error>),
+    /// An image's path has no usable file name to derive other file names from.
+    InvalidImagePath(PathBuf),
+    /// An image decoded with a width or height of zero, as some corrupt files do.
+    EmptyImage(PathBuf),
 
     DimensionMismatch {
         expected: (u32, u32),
@@ -16,9 +22,18 @@ pub enum HdtrError {
     },
 
     InvalidPipelineJson(serde_json::Error),
+    InvalidPipelineToml(toml::de::Error),
+    InvalidPipelineYaml(serde_yaml::Error),
     PipelineError(Cow<'static, str>),
     NoSaveOperationSpecified,
     ErrorWritingFile(PathBuf),
+    /// A `.cube` color lookup table couldn't be parsed.
+    InvalidLut {
+        path: PathBuf,
+        details: Cow<'static, str>,
+    },
+    /// The output format couldn't be determined from a file extension or format name.
+    UnknownOutputFormat(String),
     HDTR(Cow<'static, str>),
 }
 
@@ -33,7 +48,10 @@ macro_rules! from_err {
 }
 
 from_err!(InvalidPipelineJson, serde_json::Error);
+from_err!(InvalidPipelineToml, toml::de::Error);
+from_err!(InvalidPipelineYaml, serde_yaml::Error);
 from_err!(IO, std::io::Error);
+from_err!(Download, ureq::Error);
 from_err!(InputFileReadError, ImageError);
 from_err!(HDTR, &'static str);
 from_err!(HDTR, String);
@@ -1,11 +1,31 @@
-use image::{DynamicImage, GenericImage, GenericImageView, Pixel, RgbImage};
-use pipeline::MaskType;
+use image::{
+    codecs::{
+        gif::GifDecoder,
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType as PngFilter, PngEncoder},
+        webp::{WebPEncoder, WebPQuality},
+    },
+    AnimationDecoder, ColorType, DynamicImage, GenericImage, GenericImageView, GrayImage,
+    ImageBuffer, ImageEncoder, ImageFormat, Luma, Pixel, PixelWithColorType, RgbImage,
+};
+use pipeline::{BandSpec, DissolveSchedule, MaskChannel, MaskType, Precision, TrailCombine};
 use rayon::prelude::*;
-use std::path::{Path, PathBuf};
+use serde::Serialize;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tiff::{
+    decoder::{Decoder as TiffDecoder, DecodingResult},
+    ColorType as TiffColor, TiffError,
+};
 
 mod err;
+mod lut;
+mod metadata;
 pub mod pipeline;
 pub use err::HdtrError;
+pub use lut::Lut;
 
 pub struct InputImage {
     pub path: PathBuf,
@@ -19,6 +39,285 @@ impl InputImage {
         let im = image::open(&path)?;
         Ok(Self { path, im })
     }
+
+    /// Fails if the image has no pixels, which would otherwise blend to an empty output.
+    pub(crate) fn ensure_nonempty(&self) -> Result<(), HdtrError> {
+        match self.im.dimensions() {
+            (0, _) | (_, 0) => Err(HdtrError::EmptyImage(self.path.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Downloads and decodes the image at an `http://` or `https://` URL. The URL is kept as
+    /// the image's path.
+    pub fn from_url(url: &str) -> Result<Self, HdtrError> {
+        Ok(Self {
+            path: url.into(),
+            im: download_image(url)?,
+        })
+    }
+
+    /// Decodes every frame of an animated GIF as a separate input image.
+    ///
+    /// Since the frames share one file, frame `i` of `anim.gif` is given the path `anim_{i}.gif`
+    /// (zero-padded) so that files derived from it, such as saved masks, don't collide.
+    pub fn from_gif<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, HdtrError> {
+        let path = path.as_ref();
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let frames = GifDecoder::new(file)?.into_frames().collect_frames()?;
+
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let file_stem = path
+            .file_stem()
+            .and_then(|osstr| osstr.to_str())
+            .unwrap_or("frame");
+
+        let images = frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, frame)| Self {
+                path: parent.join(format!("{file_stem}_{i:03}.gif")),
+                im: DynamicImage::ImageRgba8(frame.into_buffer()),
+            })
+            .collect::<Vec<_>>();
+
+        if images.is_empty() {
+            return Err(HdtrError::HDTR(
+                format!("{} contains no frames", path.display()).into(),
+            ));
+        }
+
+        Ok(images)
+    }
+
+    pub(crate) fn is_gif<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("gif"))
+            .unwrap_or(false)
+    }
+}
+
+/// Describes the pixels that no mask covers, which come out black when blended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CoverageReport {
+    /// The number of pixels where every mask is black.
+    pub uncovered: u64,
+    /// The smallest `(x, y, width, height)` rectangle containing every uncovered pixel.
+    pub bounding_box: Option<(u32, u32, u32, u32)>,
+}
+
+/// An RGB image with 16 bits per channel.
+pub type Rgb16Image = ImageBuffer<image::Rgb<u16>, Vec<u16>>;
+
+/// Where a mask was placed in the atlas written by `InputImages::save_mask_atlas`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AtlasRect {
+    pub index: usize,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+/// The tiles written by `InputImages::save_tiled` or `save_tiled_from_files`, which together
+/// cover a `width` by `height` output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TileManifest {
+    pub width: u32,
+    pub height: u32,
+    pub tile: u32,
+    pub tiles: Vec<TileRect>,
+}
+
+/// Saves `canvas` as the tile at column `col` and row `row` of the output, whose top left corner
+/// is at `(x, y)`.
+fn save_tile(
+    dir: &Path,
+    col: u32,
+    row: u32,
+    (x, y): (u32, u32),
+    canvas: &RgbImage,
+) -> Result<TileRect, HdtrError> {
+    let file = format!("tile_{col}_{row}.png");
+    let dest = dir.join(&file);
+    canvas
+        .save_with_format(&dest, ImageFormat::Png)
+        .map_err(|_| HdtrError::ErrorWritingFile(dest))?;
+    let (w, h) = canvas.dimensions();
+    Ok(TileRect { file, x, y, w, h })
+}
+
+/// Writes `manifest.json` to `dir`, locating `tiles` in a `width` by `height` output, and returns
+/// the manifest.
+fn write_tile_manifest(
+    dir: &Path,
+    width: u32,
+    height: u32,
+    tile: u32,
+    tiles: Vec<TileRect>,
+) -> Result<TileManifest, HdtrError> {
+    let manifest = TileManifest {
+        width,
+        height,
+        tile,
+        tiles,
+    };
+    std::fs::write(
+        dir.join("manifest.json"),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(manifest)
+}
+
+/// How masks that are generated per pixel, rather than stored, weight the images.
+struct GeneratedMasks<'a> {
+    /// The mask type of each image.
+    mask_types: &'a [MaskType],
+    normalize: bool,
+    antialias: bool,
+}
+
+/// Where a tile written by `InputImages::save_tiled` or `save_tiled_from_files` belongs in the
+/// output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TileRect {
+    pub file: String,
+    pub x: u32,
+    pub y: u32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl CoverageReport {
+    pub fn is_fully_covered(&self) -> bool {
+        self.uncovered == 0
+    }
+
+    fn merge(self, other: Self) -> Self {
+        let bounding_box = match (self.bounding_box, other.bounding_box) {
+            (Some((x0, y0, w0, h0)), Some((x1, y1, w1, h1))) => {
+                let (x, y) = (x0.min(x1), y0.min(y1));
+                let x_end = (x0 + w0).max(x1 + w1);
+                let y_end = (y0 + h0).max(y1 + h1);
+                Some((x, y, x_end - x, y_end - y))
+            }
+            (a, b) => a.or(b),
+        };
+
+        Self {
+            uncovered: self.uncovered + other.uncovered,
+            bounding_box,
+        }
+    }
+}
+
+/// A float type that blends and mask normalization can accumulate in.
+trait Accumulate:
+    Copy
+    + From<u8>
+    + Into<f64>
+    + std::ops::AddAssign
+    + std::ops::Mul<Output = Self>
+    + std::ops::Div<Output = Self>
+    + Send
+    + Sync
+{
+    /// Converts a sum of mask values, which may not fit in a `u16`.
+    fn from_u32(value: u32) -> Self;
+}
+
+impl Accumulate for f32 {
+    fn from_u32(value: u32) -> Self {
+        value as f32
+    }
+}
+
+impl Accumulate for f64 {
+    fn from_u32(value: u32) -> Self {
+        value as f64
+    }
+}
+
+/// Blends frames one at a time, keeping running per-channel sums of each frame weighted by its
+/// mask, along with the sum of the weights. Only the frame being added needs to be in memory, so
+/// long sequences can be blended without loading every frame.
+pub struct Accumulator {
+    width: u32,
+    height: u32,
+    /// Weighted sums of each channel, in row-major order.
+    sums: Vec<[f64; 3]>,
+    /// The sums of the masks' weights for each channel, in row-major order.
+    weights: Vec<[f64; 3]>,
+}
+
+impl Accumulator {
+    pub fn new(width: u32, height: u32) -> Self {
+        let len = (width * height) as usize;
+        Self {
+            width,
+            height,
+            sums: vec![[0.; 3]; len],
+            weights: vec![[0.; 3]; len],
+        }
+    }
+
+    /// Adds `img`, weighted per channel by `mask`. Both must have the accumulator's dimensions.
+    pub fn add_frame(&mut self, img: &DynamicImage, mask: &DynamicImage) -> Result<(), HdtrError> {
+        let expected = (self.width, self.height);
+        for (received, details) in [
+            (
+                img.dimensions(),
+                "Frame has different dimensions than the accumulator",
+            ),
+            (
+                mask.dimensions(),
+                "Mask has different dimensions than the accumulator",
+            ),
+        ] {
+            if received != expected {
+                return Err(HdtrError::DimensionMismatch {
+                    expected,
+                    received,
+                    details: details.into(),
+                });
+            }
+        }
+
+        let (img, mask) = (img.to_rgb8(), mask.to_rgb8());
+        let row = self.width as usize;
+        self.sums
+            .par_chunks_mut(row)
+            .zip(self.weights.par_chunks_mut(row))
+            .enumerate()
+            .for_each(|(y, (sums, weights))| {
+                for (x, (sum, weight)) in sums.iter_mut().zip(weights).enumerate() {
+                    let p = img.get_pixel(x as u32, y as u32);
+                    let m = mask.get_pixel(x as u32, y as u32);
+                    for c in 0..3 {
+                        let w = m[c] as f64 / 255.;
+                        sum[c] += p[c] as f64 * w;
+                        weight[c] += w;
+                    }
+                }
+            });
+
+        Ok(())
+    }
+
+    /// The weighted average of the frames added so far. Pixels with no weight are black.
+    pub fn finalize(self) -> RgbImage {
+        let mut canvas = RgbImage::new(self.width, self.height);
+        for ((pixel, sum), weight) in canvas.pixels_mut().zip(self.sums).zip(self.weights) {
+            for c in 0..3 {
+                if weight[c] > 0. {
+                    pixel[c] = (sum[c] / weight[c]).round().clamp(0., 255.) as u8;
+                }
+            }
+        }
+        canvas
+    }
 }
 
 pub struct InputImages {
@@ -33,12 +332,14 @@ impl InputImages {
         let mut it = paths.iter();
 
         let input_image = InputImage::new(it.next().expect("Non-empty slice"))?;
+        input_image.ensure_nonempty()?;
         let width = input_image.im.width();
         let height = input_image.im.height();
         let mut images = vec![input_image];
 
         for p in it {
             let input_image = InputImage::new(p)?;
+            input_image.ensure_nonempty()?;
             assert_eq!(width, input_image.im.width());
             assert_eq!(height, input_image.im.height());
             images.push(input_image);
@@ -54,65 +355,144 @@ impl InputImages {
         })
     }
 
+    /// Uses each frame of an animated GIF as an input image.
+    pub fn from_gif<P: AsRef<Path>>(path: P) -> Result<Self, HdtrError> {
+        let images = InputImage::from_gif(path)?;
+        // GIF frames are composited onto the full logical screen, so they all share its size.
+        let (width, height) = images[0].im.dimensions();
+        let masks = Self::default_masks(&images, width, height);
+
+        Ok(Self {
+            images,
+            masks,
+            width,
+            height,
+        })
+    }
+
+    /// Builds `count` solid-color images of the given size in memory, for tests.
+    #[cfg(test)]
+    pub(crate) fn synthetic(count: usize, width: u32, height: u32) -> Self {
+        let images = (0..count)
+            .map(|i| {
+                let color = hue_to_rgb(i as f64 / count as f64).map(|c| (c * 255.) as u8);
+                InputImage {
+                    path: format!("image{i}.png").into(),
+                    im: DynamicImage::ImageRgb8(RgbImage::from_pixel(
+                        width,
+                        height,
+                        image::Rgb(color),
+                    )),
+                }
+            })
+            .collect::<Vec<_>>();
+        let masks = Self::default_masks(&images, width, height);
+
+        Self {
+            images,
+            masks,
+            width,
+            height,
+        }
+    }
+
+    /// The number of input images.
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    /// The `(width, height)` shared by every image.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, InputImage> {
+        self.images.iter()
+    }
+
     fn default_masks(images: &[InputImage], width: u32, height: u32) -> Vec<DynamicImage> {
         let mut masks = Vec::new();
 
-        // The precise width (with fractional part) of each stripe. This avoids accumulating
-        // remainders that aren't handled.
-        let width_f = width as f64 / images.len() as f64;
-
         for i in 0..images.len() {
-            let mut canvas = RgbImage::new(width, height);
+            let mut canvas = GrayImage::new(width, height);
 
-            let x_start = (width_f * (i as f64)) as u32;
-            let x_end = (width_f * ((i + 1) as f64)) as u32;
+            let x_start = band_edge(i, images.len(), width);
+            let x_end = band_edge(i + 1, images.len(), width);
 
             for x in x_start..x_end {
                 for y in 0..height {
-                    let p = *Pixel::from_slice(&[255, 255, 255]);
-                    canvas.put_pixel(x, y, p);
+                    canvas.put_pixel(x, y, Luma([255]));
                 }
             }
 
-            masks.push(DynamicImage::ImageRgb8(canvas));
+            masks.push(DynamicImage::ImageLuma8(canvas));
         }
 
         masks
     }
 
+    /// Scales the masks so that, for each channel, their values at every pixel sum to 255.
     pub fn normalize_masks(&mut self) {
-        // Sum up the contribution of each mask at each pixel
+        self.normalize_masks_with_precision(Precision::Double)
+    }
+
+    /// Like `normalize_masks`, but computes each mask's share in the given precision.
+    pub fn normalize_masks_with_precision(&mut self, precision: Precision) {
+        match precision {
+            Precision::Single => self.normalize_masks_as::<f32>(),
+            Precision::Double => self.normalize_masks_as::<f64>(),
+        }
+    }
+
+    fn normalize_masks_as<F: Accumulate>(&mut self) {
+        // Sum up the contribution of each mask at each pixel, per channel
         let sums = {
-            let mut sums = vec![0u32; (self.width * self.height) as usize];
+            let mut sums = vec![[0u32; 3]; (self.width * self.height) as usize];
 
             for x in 0..self.width {
                 for y in 0..self.height {
                     let idx = (self.width * y + x) as usize;
                     for i in 0..self.images.len() {
-                        let value = self.masks[i].get_pixel(x, y).to_rgb().0[0];
-                        sums[idx] += value as u32;
+                        let value = self.masks[i].get_pixel(x, y).to_rgb().0;
+                        for c in 0..3 {
+                            sums[idx][c] += value[c] as u32;
+                        }
                     }
                 }
             }
             sums
         };
 
+        // Masks that all have a single channel, such as generated ones, stay that way.
+        let single_channel = self.masks.iter().all(|m| m.color() == ColorType::L8);
+
         // Modify every mask to be [0,255] according to how much it contributed
         let mut masks = (0..self.masks.len())
             .into_par_iter()
             .map(|i| {
-                let mask = RgbImage::new(self.width, self.height);
-                let mut mask = DynamicImage::ImageRgb8(mask);
+                let mut mask = if single_channel {
+                    DynamicImage::new_luma8(self.width, self.height)
+                } else {
+                    DynamicImage::new_rgb8(self.width, self.height)
+                };
                 for x in 0..self.width {
                     for y in 0..self.height {
                         let idx = (self.width * y + x) as usize;
                         let denominator = sums[idx];
 
-                        let numerator = self.masks[i].get_pixel(x, y).to_rgb().0[0];
+                        let numerator = self.masks[i].get_pixel(x, y).to_rgb().0;
 
-                        let scaled = (255. * numerator as f64 / denominator as f64) as u8;
-                        let rgb = [scaled, scaled, scaled, 255];
-                        let pixel = Pixel::from_slice(&rgb[..]);
+                        let mut rgba = [0, 0, 0, 255];
+                        for c in 0..3 {
+                            let share = F::from(255u8) * F::from(numerator[c])
+                                / F::from_u32(denominator[c]);
+                            rgba[c] = share.into() as u8;
+                        }
+                        let pixel = Pixel::from_slice(&rgba[..]);
                         mask.put_pixel(x, y, *pixel);
                     }
                 }
@@ -124,217 +504,2301 @@ impl InputImages {
         self.masks = masks.into_iter().map(|(_, mask)| mask).collect();
     }
 
-    pub fn save<P: AsRef<Path>>(&self, destination: P) -> Result<(), HdtrError> {
-        let mut canvas = RgbImage::new(self.width, self.height);
+    /// Like `normalize_masks`, but each mask's share of a pixel is `exp(w / temperature)` relative
+    /// to the sum over all masks, where `w` is its value scaled to `[0, 1]`. A low temperature
+    /// approaches giving the pixel entirely to the heaviest mask, a high one an even split.
+    pub fn normalize_masks_softmax(&mut self, temperature: f64) {
+        assert!(temperature > 0., "Temperature must be positive");
+        let single_channel = self.masks.iter().all(|m| m.color() == ColorType::L8);
+        let (width, height) = (self.width, self.height);
 
-        let pixels = (0..self.width)
+        let shares = (0..height)
             .into_par_iter()
-            .map(|x| {
-                let mut pixels = Vec::with_capacity(self.height as usize);
-                for y in 0..self.height {
-                    let (mut r_out, mut g_out, mut b_out) = (0., 0., 0.);
-
-                    for i in 0..self.masks.len() {
-                        // Input pixel
-                        let p = self.images[i].im.get_pixel(x, y).to_rgb();
-                        // mask pixel
-                        let pm = self.masks[i].get_pixel(x, y).to_rgb();
-
-                        // Add to the output the value of this pixel multiplied by [0, 1]
-                        r_out += p[0] as f64 * (pm[0] as f64 / 255.);
-                        g_out += p[1] as f64 * (pm[1] as f64 / 255.);
-                        b_out += p[2] as f64 * (pm[2] as f64 / 255.);
+            .flat_map_iter(|y| {
+                let masks = &self.masks;
+                (0..width).map(move |x| {
+                    let values = masks
+                        .iter()
+                        .map(|m| m.get_pixel(x, y).to_rgb().0)
+                        .collect::<Vec<_>>();
+
+                    let mut shares = vec![[0u8; 3]; values.len()];
+                    for c in 0..3 {
+                        // Subtracting the largest exponent keeps `exp` from overflowing.
+                        let exponents = values
+                            .iter()
+                            .map(|v| v[c] as f64 / 255. / temperature)
+                            .collect::<Vec<_>>();
+                        let max = exponents.iter().cloned().fold(f64::MIN, f64::max);
+                        let exps = exponents
+                            .iter()
+                            .map(|e| (e - max).exp())
+                            .collect::<Vec<_>>();
+                        let sum = exps.iter().sum::<f64>();
+                        for (share, e) in shares.iter_mut().zip(&exps) {
+                            share[c] = (255. * e / sum) as u8;
+                        }
                     }
-
-                    let r = r_out as u8;
-                    let g = g_out as u8;
-                    let b = b_out as u8;
-                    let rgb = [r, g, b];
-                    let p: image::Rgb<u8> = *Pixel::from_slice(&rgb[..]);
-                    //canvas.put_pixel(x, y, *p);
-                    pixels.push(p);
-                }
-                pixels
+                    shares
+                })
             })
             .collect::<Vec<_>>();
 
-        for (x, pxs) in pixels.into_iter().enumerate() {
-            for (y, p) in pxs.into_iter().enumerate() {
-                canvas.put_pixel(x as u32, y as u32, p);
-            }
+        for (i, mask) in self.masks.iter_mut().enumerate() {
+            let value = |x: u32, y: u32| shares[(y * width + x) as usize][i];
+            *mask = if single_channel {
+                DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, y| {
+                    Luma([value(x, y)[0]])
+                }))
+            } else {
+                DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                    image::Rgb(value(x, y))
+                }))
+            };
         }
+    }
+
+    /// Blends the images according to their masks and saves the result, choosing the format from
+    /// `destination`'s extension.
+    ///
+    /// Each channel of a mask weights the same channel of its image, so masks need not be gray;
+    /// see `create_masks_rgb`.
+    pub fn save<P: AsRef<Path>>(&self, destination: P) -> Result<(), HdtrError> {
+        let format = format_from_path(destination.as_ref())?;
+        self.save_with_format(destination, format)
+    }
 
-        canvas.save(destination)?;
+    /// Blends the images according to their masks and saves the result in the given format,
+    /// regardless of `destination`'s extension.
+    pub fn save_with_format<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        format: ImageFormat,
+    ) -> Result<(), HdtrError> {
+        self.blend_to_image()
+            .save_with_format(destination, format)?;
 
         Ok(())
     }
 
-    pub fn save_masks(&self) -> Result<(), HdtrError> {
-        let pairs = self.images.iter().zip(&self.masks).collect::<Vec<_>>();
-
-        pairs
-            .into_par_iter()
-            .map(|(im, m)| {
-                let parent = im
-                    .path
-                    .parent()
-                    .expect("Couldn't get parent directory for image");
+    /// Blends only the images at `indices`, with their masks renormalized so that at each pixel
+    /// the selected masks' weights sum to full weight per channel. Pixels none of them cover are
+    /// black.
+    pub fn blend_subset(&self, indices: &[usize]) -> Result<RgbImage, HdtrError> {
+        if indices.is_empty() {
+            return Err(HdtrError::HDTR("No images were selected to blend".into()));
+        }
+        if let Some(index) = indices.iter().find(|&&i| i >= self.images.len()) {
+            return Err(HdtrError::HDTR(
+                format!(
+                    "Index {index} is out of range for {} images",
+                    self.images.len()
+                )
+                .into(),
+            ));
+        }
 
-                let file_stem = im
-                    .path
-                    .file_stem()
-                    .and_then(|osstr| osstr.to_str())
-                    .expect("Couldn't get file name for image");
+        let mut canvas = RgbImage::new(self.width, self.height);
+        canvas
+            .par_chunks_mut(3 * self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = y as u32;
+                for (x, out) in row.chunks_mut(3).enumerate() {
+                    let x = x as u32;
+                    let (mut sums, mut weights) = ([0.; 3], [0.; 3]);
+                    for &i in indices {
+                        let p = self.images[i].im.get_pixel(x, y);
+                        let pm = self.masks[i].get_pixel(x, y).to_rgb();
+                        for c in 0..3 {
+                            sums[c] += p[c] as f64 * pm[c] as f64;
+                            weights[c] += pm[c] as f64;
+                        }
+                    }
 
-                let mask_filename = format!("{file_stem}_mask.png");
-                let mask_path = parent.join(mask_filename);
+                    for c in 0..3 {
+                        if weights[c] > 0. {
+                            out[c] = (sums[c] / weights[c]).round() as u8;
+                        }
+                    }
+                }
+            });
+        Ok(canvas)
+    }
 
-                m.save(&mask_path)
-                    .map_err(|_| HdtrError::ErrorWritingFile(mask_path))
-            })
-            .collect::<Result<Vec<()>, _>>()?;
+    /// Blends the images with weights computed by `f(image, x, y)` during compositing, ignoring
+    /// the stored masks, and saves the result. See `blend_with_mask_fn`.
+    pub fn save_with_mask_fn<P, F>(&self, destination: P, f: F) -> Result<(), HdtrError>
+    where
+        P: AsRef<Path>,
+        F: Fn(usize, u32, u32) -> f64 + Sync,
+    {
+        let format = format_from_path(destination.as_ref())?;
+        self.blend_with_mask_fn(f)
+            .save_with_format(destination, format)?;
 
         Ok(())
     }
 
-    pub fn set_mask(&mut self, index: usize, mask: DynamicImage) {
-        assert!(index < self.masks.len(), "Invalid mask index");
-        assert_eq!(self.width, mask.width());
-        assert_eq!(self.height, mask.height());
+    /// Blends the images with weights computed by `f(image, x, y)` as each pixel is composited,
+    /// so no mask images are stored. Weights are relative: each pixel is normalized by the sum of
+    /// the images' weights there, and pixels where every weight is zero are black.
+    pub fn blend_with_mask_fn<F>(&self, f: F) -> RgbImage
+    where
+        F: Fn(usize, u32, u32) -> f64 + Sync,
+    {
+        let mut canvas = RgbImage::new(self.width, self.height);
+        canvas
+            .par_chunks_mut(3 * self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = y as u32;
+                for (x, out) in row.chunks_mut(3).enumerate() {
+                    let x = x as u32;
+                    let (mut sums, mut total) = ([0.; 3], 0.);
+                    for (i, input) in self.images.iter().enumerate() {
+                        let weight = f(i, x, y);
+                        let p = input.im.get_pixel(x, y);
+                        for c in 0..3 {
+                            sums[c] += weight * p[c] as f64;
+                        }
+                        total += weight;
+                    }
 
-        self.masks[index] = mask;
+                    if total > 0. {
+                        for c in 0..3 {
+                            out[c] = (sums[c] / total).round() as u8;
+                        }
+                    }
+                }
+            });
+        canvas
     }
 
-    pub(crate) fn generate_masks(&mut self, mask_type: MaskType) {
-        let indexes = (0..self.masks.len()).collect::<Vec<_>>();
-
-        let mut masks = indexes
-            .into_par_iter()
-            .map(|i| (i, self.generate_mask(i, mask_type)))
-            .collect::<Vec<_>>();
-        masks.sort_by_key(|(idx, _)| *idx);
+    /// Blends the images according to their masks and encodes the result in `format` in memory,
+    /// for callers that send it somewhere other than a file.
+    pub fn encode(&self, format: ImageFormat) -> Result<Vec<u8>, HdtrError> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        self.blend_to_image().write_to(&mut bytes, format)?;
+        Ok(bytes.into_inner())
+    }
 
-        self.masks = masks.into_iter().map(|(_, m)| m).collect();
+    /// Saves the blended image and input `reference` side by side, separated by a thin white
+    /// divider, at full resolution.
+    pub fn save_comparison<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        reference: usize,
+    ) -> Result<(), HdtrError> {
+        self.save_comparison_scaled(destination, reference, None)
     }
 
-    fn generate_mask(&self, image_num: usize, mask_type: MaskType) -> DynamicImage {
-        let mut canvas = RgbImage::new(self.width, self.height);
+    /// Like `save_comparison`, but the result is scaled down to at most `max_width` pixels wide
+    /// if given.
+    pub fn save_comparison_scaled<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        reference: usize,
+        max_width: Option<u32>,
+    ) -> Result<(), HdtrError> {
+        if reference >= self.images.len() {
+            return Err(HdtrError::HDTR(
+                format!(
+                    "Reference {reference} is out of range for {} images",
+                    self.images.len()
+                )
+                .into(),
+            ));
+        }
 
-        let white = *Pixel::from_slice(&[255, 255, 255]);
+        let (width, height) = (self.width, self.height);
+        let mut canvas = RgbImage::from_pixel(
+            2 * width + COMPARISON_DIVIDER_WIDTH,
+            height,
+            image::Rgb([255; 3]),
+        );
+        canvas.copy_from(&self.blend_to_image(), 0, 0)?;
+        canvas.copy_from(
+            &self.images[reference].im.to_rgb8(),
+            width + COMPARISON_DIVIDER_WIDTH,
+            0,
+        )?;
+
+        let canvas = match max_width {
+            Some(max_width) if max_width < canvas.width() => {
+                let scaled_height =
+                    ((height as u64 * max_width as u64) / canvas.width() as u64).max(1) as u32;
+                image::imageops::resize(
+                    &canvas,
+                    max_width,
+                    scaled_height,
+                    image::imageops::FilterType::Triangle,
+                )
+            }
+            _ => canvas,
+        };
 
-        let width_f = self.width as f64 / self.images.len() as f64;
-        let height_f = self.height as f64 / self.images.len() as f64;
+        let format = format_from_path(destination.as_ref())?;
+        canvas.save_with_format(destination, format)?;
+        Ok(())
+    }
 
-        match mask_type {
-            MaskType::VerticalFlat => {
-                // The precise width (with fractional part) of each stripe. This avoids accumulating
-                // remainders that aren't handled.
+    /// Blends the images according to their masks, then combines that result with image `base`
+    /// using the soft-light blend mode, with `base` as the backdrop.
+    pub fn soft_light_blend(&self, base: usize) -> RgbImage {
+        assert!(base < self.images.len(), "Invalid base image index");
 
-                let x_start = (width_f * (image_num as f64)) as u32;
-                let x_end = (width_f * ((image_num + 1) as f64)) as u32;
+        let mut canvas = self.blend_to_image();
+        let base = &self.images[base].im;
 
-                for x in x_start..x_end {
-                    for y in 0..self.height {
-                        canvas.put_pixel(x, y, white);
-                    }
-                }
+        for (x, y, p) in canvas.enumerate_pixels_mut() {
+            let b = base.get_pixel(x, y).to_rgb();
+            for c in 0..3 {
+                let out = soft_light(b[c] as f64 / 255., p[c] as f64 / 255.);
+                p[c] = (out * 255.).round() as u8;
             }
-            MaskType::HorizontalFlat => {
-                // Similar to above but with banded height
+        }
 
-                let y_start = (height_f * (image_num as f64)) as u32;
-                let y_end = (height_f * ((image_num + 1) as f64)) as u32;
+        canvas
+    }
 
-                for x in 0..self.width {
-                    for y in y_start..y_end {
-                        canvas.put_pixel(x, y, white);
-                    }
-                }
-            }
-            MaskType::VerticalLogistic { k } => {
-                // Where should the most intense part be?
-                let center_x = (image_num as f64 * width_f + width_f / 2.) as u32;
+    /// Blends the images according to their masks and saves the result as a JPEG with the given
+    /// quality, from 1 (smallest) to 100 (best).
+    pub fn save_jpeg<P: AsRef<Path>>(&self, destination: P, quality: u8) -> Result<(), HdtrError> {
+        write_jpeg(&self.blend_to_image(), destination.as_ref(), quality)
+    }
 
-                for x in 0..self.width {
-                    // Get the absolute distance from the center of this slice
-                    let distance_x = (x as f64 - center_x as f64).abs();
+    /// Blends the images according to their masks and saves the result as a PNG with the given
+    /// compression level and row filter, trading encoding speed for file size.
+    pub fn save_png<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        compression: CompressionType,
+        filter: PngFilter,
+    ) -> Result<(), HdtrError> {
+        write_png(
+            &self.blend_to_image(),
+            destination.as_ref(),
+            compression,
+            filter,
+        )
+    }
 
-                    let logit = logistic(distance_x, k * width_f);
-                    let p = ((1. - logit) * 255.) as u8;
-                    let p = [p, p, p];
+    /// Whether any input has more than 8 bits per channel, such as a 16-bit TIFF or PNG.
+    pub fn is_high_bit_depth(&self) -> bool {
+        self.images.iter().any(|input| {
+            let color = input.im.color();
+            color.bytes_per_pixel() > color.channel_count()
+        })
+    }
 
-                    let p = *Pixel::from_slice(&p);
+    /// Blends the images according to their masks from their 16-bit values, so that high bit
+    /// depth inputs keep their precision. 8-bit inputs are scaled up to the same range. This
+    /// always accumulates in `f64`, whatever the pipeline's `precision`.
+    pub fn blend_to_image16(&self) -> Rgb16Image {
+        let inputs = self
+            .images
+            .iter()
+            .map(|input| input.im.to_rgb16())
+            .collect::<Vec<_>>();
 
-                    for y in 0..self.height {
-                        canvas.put_pixel(x, y, p);
+        let mut canvas = Rgb16Image::new(self.width, self.height);
+        canvas
+            .par_chunks_mut(3 * self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                let y = y as u32;
+                for (x, out) in row.chunks_mut(3).enumerate() {
+                    let x = x as u32;
+                    let mut sums = [0.; 3];
+                    for (input, mask) in inputs.iter().zip(&self.masks) {
+                        let (p, pm) = (input.get_pixel(x, y), mask.get_pixel(x, y));
+                        for c in 0..3 {
+                            sums[c] += p[c] as f64 * (pm[c] as f64 / 255.);
+                        }
+                    }
+                    for c in 0..3 {
+                        out[c] = sums[c].round().min(u16::MAX as f64) as u16;
                     }
                 }
-            }
-            MaskType::HorizontalLogistic { k } => {
-                // Where should the most intense part be?
-                let center_y = (image_num as f64 * height_f + height_f / 2.) as u32;
+            });
+        canvas
+    }
 
-                for y in 0..self.height {
-                    // Get the absolute distance from the center of this slice
-                    let distance_y = (y as f64 - center_y as f64).abs();
+    /// Blends the images according to their masks.
+    pub fn blend_to_image(&self) -> RgbImage {
+        self.blend_to_image_with_precision(Precision::Double)
+    }
 
-                    let logit = logistic(distance_y, k * height_f);
-                    let p = ((1. - logit) * 255.) as u8;
-                    let p = [p, p, p];
+    /// Like `blend_to_image`, but accumulates in the given precision.
+    pub fn blend_to_image_with_precision(&self, precision: Precision) -> RgbImage {
+        let mut canvas = RgbImage::new(self.width, self.height);
+        self.blend_into_with_precision(&mut canvas, precision)
+            .expect("Canvas was created with the images' dimensions");
+        canvas
+    }
 
-                    let p = *Pixel::from_slice(&p);
+    /// Blends the images according to their masks, writing the result into `out`, which must have
+    /// the same dimensions as the images. This lets repeated blends reuse one buffer.
+    pub fn blend_into(&self, out: &mut RgbImage) -> Result<(), HdtrError> {
+        self.blend_into_with_precision(out, Precision::Double)
+    }
 
-                    for x in 0..self.width {
-                        canvas.put_pixel(x, y, p);
-                    }
-                }
-            }
+    /// Like `blend_into`, but accumulates in the given precision.
+    pub fn blend_into_with_precision(
+        &self,
+        out: &mut RgbImage,
+        precision: Precision,
+    ) -> Result<(), HdtrError> {
+        match precision {
+            Precision::Single => self.blend_into_as::<f32>(out),
+            Precision::Double => self.blend_into_as::<f64>(out),
         }
-
-        DynamicImage::ImageRgb8(canvas)
     }
 
-    pub fn create_masks<F>(&mut self, f: F)
-    where
-        F: Fn(usize, u32, u32) -> u8,
-    {
-        for i in 0..self.masks.len() {
-            let mut canvas = RgbImage::new(self.width, self.height);
+    fn blend_into_as<F: Accumulate>(&self, out: &mut RgbImage) -> Result<(), HdtrError> {
+        let expected = (self.width, self.height);
+        let received = out.dimensions();
+        if expected != received {
+            return Err(HdtrError::DimensionMismatch {
+                expected,
+                received,
+                details: "Output buffer has different dimensions than the images".into(),
+            });
+        }
 
-            for x in 0..self.width {
-                for y in 0..self.height {
-                    let p = f(i, x, y);
-                    let slice = [p, p, p];
-                    let p = Pixel::from_slice(&slice[..]);
-                    canvas.put_pixel(x, y, *p);
-                }
-            }
+        let pixels = (0..self.width)
+            .into_par_iter()
+            .map(|x| {
+                (0..self.height)
+                    .map(|y| self.blend_pixel::<F>(x, y))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
 
-            self.masks[i] = DynamicImage::ImageRgb8(canvas);
+        for (x, pxs) in pixels.into_iter().enumerate() {
+            for (y, p) in pxs.into_iter().enumerate() {
+                out.put_pixel(x as u32, y as u32, p);
+            }
         }
+
+        Ok(())
     }
 
-    pub fn create_mask<F>(&mut self, index: usize, f: F)
-    where
-        F: Fn(u32, u32) -> u8,
-    {
-        assert!(index < self.masks.len(), "Invalid mask index");
-        let mut canvas = RgbImage::new(self.width, self.height);
+    /// The blended value of pixel `(x, y)`, accumulated in `F`.
+    fn blend_pixel<F: Accumulate>(&self, x: u32, y: u32) -> image::Rgb<u8> {
+        let zero = F::from(0u8);
+        let (mut r_out, mut g_out, mut b_out) = (zero, zero, zero);
+        let max = F::from(255u8);
 
-        for x in 0..self.width {
-            for y in 0..self.height {
-                let p = f(x, y);
-                //let p = im.get_pixel(x, y);
-                let slice = [p, p, p];
-                let p = Pixel::from_slice(&slice[..]);
+        for i in 0..self.masks.len() {
+            // Input pixel
+            let p = self.images[i].im.get_pixel(x, y).to_rgb();
+            // mask pixel
+            let pm = self.masks[i].get_pixel(x, y).to_rgb();
+
+            // Add to the output the value of this pixel multiplied by [0, 1]
+            r_out += F::from(p[0]) * (F::from(pm[0]) / max);
+            g_out += F::from(p[1]) * (F::from(pm[1]) / max);
+            b_out += F::from(p[2]) * (F::from(pm[2]) / max);
+        }
 
-                canvas.put_pixel(x, y, *p);
-            }
+        image::Rgb([r_out.into() as u8, g_out.into() as u8, b_out.into() as u8])
+    }
+
+    /// Blends the output in `tile`-pixel squares, each composited independently and in parallel
+    /// and saved to `dir` as `tile_<col>_<row>.png`. Tiles on the right and bottom edges may be
+    /// smaller. A `manifest.json` locating each tile is written alongside them and returned.
+    ///
+    /// This splits an in-memory blend; for composites too large to load whole, use
+    /// `save_tiled_from_files`.
+    pub fn save_tiled<P: AsRef<Path>>(&self, dir: P, tile: u32) -> Result<TileManifest, HdtrError> {
+        if tile == 0 {
+            return Err(HdtrError::HDTR("Tile size must be positive".into()));
         }
 
-        self.masks[index] = DynamicImage::ImageRgb8(canvas);
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let (cols, rows) = (self.width.div_ceil(tile), self.height.div_ceil(tile));
+        let tiles = (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| (col, row)))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(col, row)| {
+                let (x, y) = (col * tile, row * tile);
+                let (w, h) = (tile.min(self.width - x), tile.min(self.height - y));
+                let canvas =
+                    RgbImage::from_fn(w, h, |dx, dy| self.blend_pixel::<f64>(x + dx, y + dy));
+                save_tile(dir, col, row, (x, y), &canvas)
+            })
+            .collect::<Result<Vec<_>, HdtrError>>()?;
+
+        write_tile_manifest(dir, self.width, self.height, tile, tiles)
     }
-}
+
+    /// Like `save_tiled`, but for composites too large to hold in memory: the inputs are read
+    /// from `paths` one row of tiles at a time, keeping only one decoded input and a `tile`-pixel
+    /// strip of each. Image `i` is weighted by a generated mask of type `mask_types[i]`, computed
+    /// per pixel rather than stored, and normalized if `normalize` is set. Each input is decoded
+    /// once per row of tiles, trading time for memory.
+    pub fn save_tiled_from_files<P: AsRef<Path>, Q: AsRef<Path>>(
+        paths: &[P],
+        mask_types: &[MaskType],
+        normalize: bool,
+        dir: Q,
+        tile: u32,
+    ) -> Result<TileManifest, HdtrError> {
+        if tile == 0 {
+            return Err(HdtrError::HDTR("Tile size must be positive".into()));
+        }
+        if paths.is_empty() {
+            return Err(HdtrError::NoInputFilesSpecified);
+        }
+        if mask_types.len() != paths.len() {
+            return Err(HdtrError::HDTR(
+                format!(
+                    "{} mask types were given for {} images",
+                    mask_types.len(),
+                    paths.len()
+                )
+                .into(),
+            ));
+        }
+
+        // Only the headers are read to check the sizes.
+        let (width, height) = image::image_dimensions(&paths[0])?;
+        for path in &paths[1..] {
+            let received = image::image_dimensions(path)?;
+            if received != (width, height) {
+                return Err(HdtrError::DimensionMismatch {
+                    expected: (width, height),
+                    received,
+                    details: format!("{} differs from the first image", path.as_ref().display())
+                        .into(),
+                });
+            }
+        }
+
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let masks = GeneratedMasks {
+            mask_types,
+            normalize,
+            antialias: false,
+        };
+        let (cols, rows) = (width.div_ceil(tile), height.div_ceil(tile));
+        let mut tiles = Vec::with_capacity((cols * rows) as usize);
+        for row in 0..rows {
+            let y = row * tile;
+            let h = tile.min(height - y);
+
+            // Decoding one input at a time keeps a single full-size image in memory.
+            let strips = paths
+                .iter()
+                .map(|path| {
+                    let im = image::open(path)?;
+                    Ok(InputImage {
+                        path: path.as_ref().into(),
+                        im: DynamicImage::ImageRgb8(im.crop_imm(0, y, width, h).to_rgb8()),
+                    })
+                })
+                .collect::<Result<Vec<_>, HdtrError>>()?;
+
+            // The strips stand in for the images, at the full output's size so that the masks
+            // are generated in output coordinates.
+            let band = InputImages {
+                images: strips,
+                masks: Vec::new(),
+                width,
+                height,
+            };
+
+            let row_tiles = (0..cols)
+                .into_par_iter()
+                .map(|col| {
+                    let x = col * tile;
+                    let w = tile.min(width - x);
+                    let mut weights = vec![0.; mask_types.len()];
+                    let canvas = RgbImage::from_fn(w, h, |dx, dy| {
+                        band.generated_blend_pixel(&masks, x + dx, y + dy, y, &mut weights)
+                    });
+                    save_tile(dir, col, row, (x, y), &canvas)
+                })
+                .collect::<Result<Vec<_>, HdtrError>>()?;
+            tiles.extend(row_tiles);
+        }
+
+        write_tile_manifest(dir, width, height, tile, tiles)
+    }
+
+    /// Blends the images once with each set of masks in `mask_sets`, in place of `self.masks`,
+    /// and returns the straight average of the composites. This combines mask geometries, e.g.
+    /// vertical and horizontal bands. Every set should be normalized on its own, with one mask
+    /// per image, so that each composite carries equal weight.
+    pub fn blend_multi(&self, mask_sets: &[Vec<DynamicImage>]) -> RgbImage {
+        assert!(!mask_sets.is_empty(), "No mask sets to blend");
+        for masks in mask_sets {
+            assert_eq!(masks.len(), self.images.len(), "Mask set has wrong length");
+            for mask in masks {
+                assert_eq!(mask.dimensions(), (self.width, self.height));
+            }
+        }
+
+        let rows = (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                (0..self.width)
+                    .map(|x| {
+                        let mut sum = [0f64; 3];
+                        for masks in mask_sets {
+                            for (input, mask) in self.images.iter().zip(masks) {
+                                let p = input.im.get_pixel(x, y).to_rgb();
+                                let m = mask.get_pixel(x, y).to_rgb();
+                                for c in 0..3 {
+                                    sum[c] += p[c] as f64 * (m[c] as f64 / 255.);
+                                }
+                            }
+                        }
+                        sum.map(|v| (v / mask_sets.len() as f64) as u8)
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            image::Rgb(rows[y as usize][x as usize])
+        })
+    }
+
+    /// The index of the image whose mask has the most weight at `(x, y)`, summed over channels.
+    /// Ties go to the earlier image. Panics if `(x, y)` is outside the images or there are no masks.
+    pub fn dominant_image_at(&self, x: u32, y: u32) -> usize {
+        let weights = self.masks.iter().map(|m| {
+            let p = m.get_pixel(x, y).to_rgb();
+            p[0] as u32 + p[1] as u32 + p[2] as u32
+        });
+
+        // `max_by_key` returns the last maximum, so reverse to prefer earlier images.
+        weights
+            .enumerate()
+            .rev()
+            .max_by_key(|&(_, w)| w)
+            .map(|(i, _)| i)
+            .expect("There are no masks")
+    }
+
+    /// Renders a false-color map of which image dominates where. Each image is assigned a hue,
+    /// evenly spaced around the color wheel, which is scaled by its share of the total mask
+    /// weight at each pixel and summed. Color masks are weighted by the mean of their channels.
+    pub fn debug_contribution_map(&self) -> RgbImage {
+        let colors = (0..self.masks.len())
+            .map(|i| hue_to_rgb(i as f64 / self.masks.len() as f64))
+            .collect::<Vec<_>>();
+
+        let rows = (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                (0..self.width)
+                    .map(|x| {
+                        let weights = self
+                            .masks
+                            .iter()
+                            .map(|m| mean_weight(m, x, y))
+                            .collect::<Vec<_>>();
+                        let sum = weights.iter().sum::<f64>();
+
+                        let mut rgb = [0.; 3];
+                        if sum > 0. {
+                            for (w, color) in weights.iter().zip(&colors) {
+                                for c in 0..3 {
+                                    rgb[c] += color[c] * w / sum;
+                                }
+                            }
+                        }
+
+                        let rgb = rgb.map(|c| (c * 255.).round() as u8);
+                        *Pixel::from_slice(&rgb)
+                    })
+                    .collect::<Vec<image::Rgb<u8>>>()
+            })
+            .collect::<Vec<_>>();
+
+        RgbImage::from_fn(self.width, self.height, |x, y| rows[y as usize][x as usize])
+    }
+
+    /// Like `debug_contribution_map`, but with each image's index drawn at the center of its mask
+    /// weight, so that bands can be matched to their images at a glance.
+    pub fn debug_contribution_map_labeled(&self) -> RgbImage {
+        let mut map = self.debug_contribution_map();
+        let scale = (self.width.min(self.height) / 100).max(1);
+
+        for (i, mask) in self.masks.iter().enumerate() {
+            let (mut sum, mut sum_x, mut sum_y) = (0., 0., 0.);
+            for (x, y, _) in mask.pixels() {
+                let w = mean_weight(mask, x, y);
+                sum += w;
+                sum_x += w * x as f64;
+                sum_y += w * y as f64;
+            }
+
+            if sum > 0. {
+                let center = ((sum_x / sum) as i64, (sum_y / sum) as i64);
+                draw_number(&mut map, i, center, scale);
+            }
+        }
+
+        map
+    }
+
+    /// Writes each image as a separate RGBA layer, `layer_<i>.png`, in `dir`.
+    ///
+    /// Stacking the layers in an editor with normal blending, `layer_0` at the bottom, reproduces
+    /// the blend of normalized masks. PNG alpha is straight rather than premultiplied, so layer
+    /// `i`'s alpha is its weight divided by the combined weight of layers `0..=i`, i.e. how much
+    /// it covers everything beneath it. Color masks weight each channel differently but a layer
+    /// has one alpha, so it takes the largest channel's and adjusts its colors so the stack still
+    /// lands on the blend. The adjusted colors lie between the image's and the blend beneath, so
+    /// they never clip.
+    pub fn save_layers<P: AsRef<Path>>(&self, dir: P) -> Result<(), HdtrError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        // The running total of the weights of the layers below, and their blend, per pixel
+        let mut below = vec![[0.; 3]; (self.width * self.height) as usize];
+        let mut blend = vec![[0.; 3]; (self.width * self.height) as usize];
+
+        for (i, (input, mask)) in self.images.iter().zip(&self.masks).enumerate() {
+            let mut layer = image::RgbaImage::new(self.width, self.height);
+
+            for (x, y, p) in layer.enumerate_pixels_mut() {
+                let idx = (self.width * y + x) as usize;
+                let weight = mask.get_pixel(x, y).to_rgb().0.map(|w| w as f64);
+                let rgb = input.im.get_pixel(x, y).to_rgb().0.map(|c| c as f64);
+                let (below, blend) = (&mut below[idx], &mut blend[idx]);
+
+                let mut coverage = 0f64;
+                let mut next = *blend;
+                for c in 0..3 {
+                    let total = below[c] + weight[c];
+                    if total > 0. {
+                        coverage = coverage.max(weight[c] / total);
+                        next[c] = (below[c] * blend[c] + weight[c] * rgb[c]) / total;
+                    }
+                    below[c] = total;
+                }
+
+                let alpha = (255. * coverage).round() as u8;
+                let color = match alpha {
+                    0 => rgb,
+                    // Solves `alpha * color + (1 - alpha) * blend = next` for each channel.
+                    _ => {
+                        let a = alpha as f64 / 255.;
+                        [0, 1, 2].map(|c| blend[c] + (next[c] - blend[c]) / a)
+                    }
+                };
+                *blend = next;
+
+                let color = color.map(|c| c.round().clamp(0., 255.) as u8);
+                *p = *Pixel::from_slice(&[color[0], color[1], color[2], alpha]);
+            }
+
+            let path = dir.join(format!("layer_{i}.png"));
+            layer
+                .save(&path)
+                .map_err(|_| HdtrError::ErrorWritingFile(path))?;
+        }
+
+        Ok(())
+    }
+
+    /// Finds pixels where the summed mask weight is zero.
+    pub fn coverage_report(&self) -> CoverageReport {
+        (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                let mut report = CoverageReport::default();
+                for x in 0..self.width {
+                    let covered = self.masks.iter().any(|m| {
+                        let p = m.get_pixel(x, y).to_rgb();
+                        p[0] > 0 || p[1] > 0 || p[2] > 0
+                    });
+
+                    if !covered {
+                        report = report.merge(CoverageReport {
+                            uncovered: 1,
+                            bounding_box: Some((x, y, 1, 1)),
+                        });
+                    }
+                }
+                report
+            })
+            .reduce(CoverageReport::default, CoverageReport::merge)
+    }
+
+    /// The paths `save_masks` writes each image's mask to: `{stem}_mask.png` alongside the image.
+    pub fn mask_paths(&self) -> Result<Vec<PathBuf>, HdtrError> {
+        self.mask_paths_to(None, DEFAULT_MASK_SUFFIX)
+    }
+
+    /// The paths `save_masks_to` writes each image's mask to: `{stem}{suffix}.png` in `dir`, or
+    /// alongside the image if `dir` is `None`.
+    pub fn mask_paths_to(
+        &self,
+        dir: Option<&Path>,
+        suffix: &str,
+    ) -> Result<Vec<PathBuf>, HdtrError> {
+        self.images
+            .iter()
+            .map(|im| {
+                // A bare file name is relative to the current directory.
+                let parent = dir.or_else(|| im.path.parent()).unwrap_or(Path::new(""));
+
+                let file_stem = im
+                    .path
+                    .file_stem()
+                    .and_then(|osstr| osstr.to_str())
+                    .ok_or_else(|| HdtrError::InvalidImagePath(im.path.clone()))?;
+
+                Ok(parent.join(format!("{file_stem}{suffix}.png")))
+            })
+            .collect()
+    }
+
+    pub fn save_masks(&self) -> Result<(), HdtrError> {
+        self.save_masks_to(None, DEFAULT_MASK_SUFFIX)
+    }
+
+    /// Saves each image's mask to the path given by `mask_paths_to`, creating `dir` if needed.
+    pub fn save_masks_to(&self, dir: Option<&Path>, suffix: &str) -> Result<(), HdtrError> {
+        if let Some(dir) = dir {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let pairs = self
+            .mask_paths_to(dir, suffix)?
+            .into_iter()
+            .zip(&self.masks)
+            .collect::<Vec<_>>();
+
+        pairs
+            .into_par_iter()
+            .map(|(mask_path, m)| {
+                m.save(&mask_path)
+                    .map_err(|_| HdtrError::ErrorWritingFile(mask_path))
+            })
+            .collect::<Result<Vec<()>, _>>()?;
+
+        Ok(())
+    }
+
+    /// Packs every mask, at full resolution, into a grid in one grayscale image at `image_dest`,
+    /// and writes a JSON array of `{index, x, y, w, h}` rectangles locating each to `json_dest`.
+    /// The grid is as close to square as possible, filled left to right and then top to bottom.
+    pub fn save_mask_atlas<P: AsRef<Path>, Q: AsRef<Path>>(
+        &self,
+        image_dest: P,
+        json_dest: Q,
+    ) -> Result<Vec<AtlasRect>, HdtrError> {
+        let count = self.masks.len() as u32;
+        let cols = (count as f64).sqrt().ceil().max(1.) as u32;
+        let rows = count.div_ceil(cols).max(1);
+
+        let mut atlas = GrayImage::new(cols * self.width, rows * self.height);
+        let mut rects = Vec::with_capacity(self.masks.len());
+        for (index, mask) in self.masks.iter().enumerate() {
+            let (col, row) = (index as u32 % cols, index as u32 / cols);
+            let (x, y) = (col * self.width, row * self.height);
+            atlas.copy_from(&mask.to_luma8(), x, y)?;
+            rects.push(AtlasRect {
+                index,
+                x,
+                y,
+                w: self.width,
+                h: self.height,
+            });
+        }
+
+        let image_dest = image_dest.as_ref();
+        atlas
+            .save_with_format(image_dest, format_from_path(image_dest)?)
+            .map_err(|_| HdtrError::ErrorWritingFile(image_dest.into()))?;
+        std::fs::write(json_dest, serde_json::to_string(&rects)?)?;
+        Ok(rects)
+    }
+
+    /// Builds an image where each pixel is taken from whichever input is sharpest there, as in
+    /// focus stacking. Masks are not used.
+    pub fn focus_stack(&self) -> RgbImage {
+        self.focus_stack_with_radius(FOCUS_STACK_RADIUS)
+    }
+
+    /// Like `focus_stack`, but sharpness (Laplacian magnitude) is summed over a window extending
+    /// `radius` pixels around each pixel. Larger windows smooth the selection and avoid speckle;
+    /// a radius of 0 compares single pixels. Ties go to the earlier image.
+    pub fn focus_stack_with_radius(&self, radius: u32) -> RgbImage {
+        let width = self.width as usize;
+        let mut best = vec![(0, f64::NEG_INFINITY); width * self.height as usize];
+
+        for (i, input) in self.images.iter().enumerate() {
+            let contrast = local_contrast(&input.im, radius);
+            best.par_iter_mut()
+                .zip(contrast.par_iter())
+                .for_each(|(b, &c)| {
+                    if c > b.1 {
+                        *b = (i, c);
+                    }
+                });
+        }
+
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let (i, _) = best[y as usize * width + x as usize];
+            self.images[i].im.get_pixel(x, y).to_rgb()
+        })
+    }
+
+    /// Replaces the masks with ones generated from content: the image is divided into `tile` by
+    /// `tile` pixel tiles, and each tile is assigned wholly to the image whose luminance varies
+    /// most within it, i.e. the sharpest. Tiles at the right and bottom edges may be smaller.
+    pub fn generate_sharpness_masks(&mut self, tile: u32) -> Result<(), HdtrError> {
+        if tile == 0 {
+            return Err(HdtrError::HDTR("Tile size must be positive".into()));
+        }
+
+        let cols = self.width.div_ceil(tile) as usize;
+        let rows = self.height.div_ceil(tile) as usize;
+
+        // The luminance variance of every tile of every image
+        let variances = self
+            .images
+            .par_iter()
+            .map(|input| {
+                let gray = input.im.to_luma8();
+                let mut sums = vec![(0f64, 0f64, 0u32); cols * rows];
+                for (x, y, p) in gray.enumerate_pixels() {
+                    let v = p[0] as f64;
+                    let t = &mut sums[(y / tile) as usize * cols + (x / tile) as usize];
+                    *t = (t.0 + v, t.1 + v * v, t.2 + 1);
+                }
+
+                sums.into_iter()
+                    .map(|(sum, sum_sq, n)| {
+                        let mean = sum / n as f64;
+                        sum_sq / n as f64 - mean * mean
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        // Ties go to the earliest image
+        let winners = (0..cols * rows)
+            .map(|t| {
+                (0..variances.len()).fold(0, |best, i| {
+                    if variances[i][t] > variances[best][t] {
+                        i
+                    } else {
+                        best
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.masks = (0..self.images.len())
+            .into_par_iter()
+            .map(|i| {
+                let mask = RgbImage::from_fn(self.width, self.height, |x, y| {
+                    let t = (y / tile) as usize * cols + (x / tile) as usize;
+                    let value = if winners[t] == i { 255 } else { 0 };
+                    image::Rgb([value; 3])
+                });
+                DynamicImage::ImageRgb8(mask)
+            })
+            .collect();
+
+        Ok(())
+    }
+
+    /// Builds an image where each channel of each pixel is the `p`th percentile of the inputs'
+    /// values there, interpolating between the two nearest. `p` of 0 gives the darkest value
+    /// (darken), 0.5 the median, and 1 the brightest (lighten). Masks are not used.
+    pub fn percentile_blend(&self, p: f64) -> RgbImage {
+        assert!(
+            (0. ..=1.).contains(&p),
+            "Percentile must be between 0 and 1"
+        );
+        let rank = p * (self.images.len().max(1) - 1) as f64;
+        let (lower, frac) = (rank.floor() as usize, rank.fract());
+
+        let rows = (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                let mut values = vec![0u8; self.images.len()];
+                (0..self.width)
+                    .map(|x| {
+                        let mut out = [0u8; 3];
+                        for (c, out) in out.iter_mut().enumerate() {
+                            for (v, input) in values.iter_mut().zip(&self.images) {
+                                *v = input.im.get_pixel(x, y)[c];
+                            }
+                            values.sort_unstable();
+
+                            let low = values[lower] as f64;
+                            let high = values[(lower + 1).min(values.len() - 1)] as f64;
+                            *out = (low + (high - low) * frac).round() as u8;
+                        }
+                        out
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            image::Rgb(rows[y as usize][x as usize])
+        })
+    }
+
+    /// Builds an image where each channel of each pixel is the largest absolute difference between
+    /// consecutive inputs there, so that regions with motion are bright and static ones dark.
+    /// Masks are not used.
+    pub fn difference_map(&self) -> RgbImage {
+        let mut canvas = RgbImage::new(self.width, self.height);
+        canvas
+            .par_chunks_mut(3 * self.width as usize)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, out) in row.chunks_mut(3).enumerate() {
+                    let (x, y) = (x as u32, y as u32);
+                    for pair in self.images.windows(2) {
+                        let (a, b) = (pair[0].im.get_pixel(x, y), pair[1].im.get_pixel(x, y));
+                        for c in 0..3 {
+                            out[c] = out[c].max(a[c].abs_diff(b[c]));
+                        }
+                    }
+                }
+            });
+        canvas
+    }
+
+    /// Averages the whole inputs, weighting each by its position in the sequence according to
+    /// `schedule`, for a cross-dissolve through time. Masks are not used.
+    pub fn time_dissolve(&self, schedule: DissolveSchedule) -> RgbImage {
+        let weights = dissolve_weights(self.images.len(), schedule);
+        let mut sums = vec![0.; 3 * (self.width * self.height) as usize];
+        for (input, weight) in self.images.iter().zip(&weights) {
+            let rgb = input.im.to_rgb8();
+            sums.par_iter_mut()
+                .zip(rgb.as_raw().par_iter())
+                .for_each(|(sum, &value)| *sum += weight * value as f64);
+        }
+
+        let pixels = sums.into_iter().map(|sum| sum.round() as u8).collect();
+        RgbImage::from_raw(self.width, self.height, pixels).expect("One sum per subpixel")
+    }
+
+    /// Merges the whole inputs with the `i`th of `N` weighted by `decay` to the power `N - 1 - i`,
+    /// so older frames fade behind the newest, as a light trail. Masks are not used.
+    pub fn motion_trail(&self, decay: f64, combine: TrailCombine) -> RgbImage {
+        let count = self.images.len();
+        let mut weights = (0..count)
+            .map(|i| decay.powi((count - 1 - i) as i32))
+            .collect::<Vec<_>>();
+        if combine == TrailCombine::Average {
+            let total = weights.iter().sum::<f64>();
+            weights.iter_mut().for_each(|w| *w /= total);
+        }
+
+        let mut out = vec![0.; 3 * (self.width * self.height) as usize];
+        for (input, weight) in self.images.iter().zip(&weights) {
+            let rgb = input.im.to_rgb8();
+            out.par_iter_mut()
+                .zip(rgb.as_raw().par_iter())
+                .for_each(|(out, &value)| {
+                    let value = weight * value as f64;
+                    match combine {
+                        TrailCombine::Lighten => *out = value.max(*out),
+                        TrailCombine::Average => *out += value,
+                    }
+                });
+        }
+
+        let pixels = out.into_iter().map(|v| v.round() as u8).collect();
+        RgbImage::from_raw(self.width, self.height, pixels).expect("One value per subpixel")
+    }
+
+    /// Builds an image where each channel of each pixel is the mean of the inputs' values there,
+    /// after discarding outliers such as hot pixels or satellite trails. Each of `iterations`
+    /// passes drops the values more than `sigma` standard deviations from the mean of those
+    /// remaining. Masks are not used.
+    pub fn sigma_clip_blend(&self, sigma: f64, iterations: u32) -> RgbImage {
+        let rows = (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                let mut values = Vec::with_capacity(self.images.len());
+                (0..self.width)
+                    .map(|x| {
+                        let mut out = [0u8; 3];
+                        for (c, out) in out.iter_mut().enumerate() {
+                            values.clear();
+                            values
+                                .extend(self.images.iter().map(|i| i.im.get_pixel(x, y)[c] as f64));
+
+                            let mut mean = values.iter().sum::<f64>() / values.len() as f64;
+                            for _ in 0..iterations {
+                                let variance =
+                                    values.iter().map(|v| (v - mean).powi(2)).sum::<f64>()
+                                        / values.len() as f64;
+                                let limit = sigma * variance.sqrt();
+                                let kept =
+                                    values.iter().filter(|v| (*v - mean).abs() <= limit).count();
+
+                                // Stop once nothing is clipped, or if everything would be, which
+                                // would leave no mean.
+                                if kept == values.len() || kept == 0 {
+                                    break;
+                                }
+                                values.retain(|v| (v - mean).abs() <= limit);
+                                mean = values.iter().sum::<f64>() / values.len() as f64;
+                            }
+                            *out = mean.round() as u8;
+                        }
+                        out
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            image::Rgb(rows[y as usize][x as usize])
+        })
+    }
+
+    /// Scales each mask by its image's local contrast relative to the sharpest image at that
+    /// pixel, so detail is favored where masks overlap. Where every image is equally flat the
+    /// masks are unchanged. Masks should be normalized afterwards.
+    pub fn apply_contrast_weights(&mut self) {
+        let radius = FOCUS_STACK_RADIUS;
+
+        // The maps are recomputed in the second pass rather than kept, to bound memory.
+        let mut max = vec![0f64; (self.width * self.height) as usize];
+        for input in &self.images {
+            let contrast = local_contrast(&input.im, radius);
+            max.par_iter_mut()
+                .zip(contrast.par_iter())
+                .for_each(|(m, &c)| *m = m.max(c));
+        }
+
+        let width = self.width;
+        self.masks = self
+            .images
+            .par_iter()
+            .zip(self.masks.par_iter())
+            .map(|(input, mask)| {
+                let contrast = local_contrast(&input.im, radius);
+                let mut mask = mask.to_rgb8();
+                for (x, y, p) in mask.enumerate_pixels_mut() {
+                    let idx = (width * y + x) as usize;
+                    // Offsetting both terms keeps flat areas from dividing by zero.
+                    let scale = (contrast[idx] + 1.) / (max[idx] + 1.);
+                    for c in 0..3 {
+                        p[c] = (p[c] as f64 * scale).round() as u8;
+                    }
+                }
+                DynamicImage::ImageRgb8(mask)
+            })
+            .collect();
+    }
+
+    /// Scales each mask by its image's overall sharpness, measured as gradient energy, relative to
+    /// the sharpest image, so that crisp frames dominate blurry ones. Returns each image's weight.
+    /// Unlike `apply_contrast_weights`, every pixel of a mask is scaled by the same amount. Masks
+    /// should be normalized afterwards.
+    pub fn apply_sharpness_weights(&mut self) -> Vec<f64> {
+        let sharpness = self
+            .images
+            .par_iter()
+            .map(|input| gradient_energy(&input.im))
+            .collect::<Vec<_>>();
+        let max = sharpness.iter().cloned().fold(0., f64::max);
+
+        // If every image is perfectly flat, none is sharper than another.
+        let weights = sharpness
+            .iter()
+            .map(|&s| if max > 0. { s / max } else { 1. })
+            .collect::<Vec<_>>();
+
+        self.masks
+            .par_iter_mut()
+            .zip(weights.par_iter())
+            .for_each(|(mask, &weight)| {
+                let mut scaled = mask.to_rgb8();
+                for p in scaled.pixels_mut() {
+                    for c in 0..3 {
+                        p[c] = (p[c] as f64 * weight).round() as u8;
+                    }
+                }
+                *mask = DynamicImage::ImageRgb8(scaled);
+            });
+
+        weights
+    }
+
+    /// Sets mask `index` from the luminance of `img` rather than its individual channels, so that
+    /// masks painted in color are weighted by their perceived brightness.
+    pub fn set_mask_from_luminance(&mut self, index: usize, img: DynamicImage) {
+        self.set_mask(index, luminance_mask(&img));
+    }
+
+    /// Sets mask `index` to white where the luminance of image `source` falls within
+    /// `low..=high` and black elsewhere, for luminosity-style masks such as taking the bright
+    /// regions of one frame and the dark regions of another.
+    pub fn mask_from_threshold(&mut self, index: usize, source: usize, low: u8, high: u8) {
+        assert!(index < self.masks.len(), "Invalid mask index");
+        assert!(source < self.images.len(), "Invalid image index");
+
+        let mut mask = self.images[source].im.to_luma8();
+        mask.par_chunks_mut(1).for_each(|p| {
+            p[0] = if (low..=high).contains(&p[0]) { 255 } else { 0 };
+        });
+        self.masks[index] = DynamicImage::ImageLuma8(mask);
+    }
+
+    /// Aligns every image (and its mask, if any) to the first by estimating an integer
+    /// translation within `search` pixels in each direction, returning each image's offset.
+    /// Pixels shifted in from outside the image repeat its edge.
+    pub fn align(&mut self, search: u32) -> Vec<(i32, i32)> {
+        let Some((reference, rest)) = self.images.split_first_mut() else {
+            return Vec::new();
+        };
+        let reference = reference.im.to_luma8();
+
+        let mut offsets = vec![(0, 0)];
+        offsets.par_extend(rest.par_iter_mut().map(|input| {
+            let offset = estimate_offset(&reference, &input.im.to_luma8(), search);
+            if offset != (0, 0) {
+                input.im = shift_image(&input.im, offset);
+            }
+            offset
+        }));
+
+        for (mask, &offset) in self.masks.iter_mut().zip(&offsets) {
+            if offset != (0, 0) {
+                *mask = shift_image(mask, offset);
+            }
+        }
+
+        offsets
+    }
+
+    /// Scales each image's brightness so that its mean luminance matches the median frame's,
+    /// evening out exposure drift between frames. Returns the factor each image was scaled by.
+    pub fn match_exposure(&mut self) -> Vec<f64> {
+        let means = self
+            .images
+            .par_iter()
+            .map(|input| {
+                let gray = input.im.to_luma8();
+                let total = gray.pixels().map(|p| p[0] as u64).sum::<u64>();
+                total as f64 / (gray.width() as u64 * gray.height() as u64).max(1) as f64
+            })
+            .collect::<Vec<_>>();
+
+        let mut sorted = means.clone();
+        sorted.sort_by(f64::total_cmp);
+        let Some(&median) = sorted.get(sorted.len() / 2) else {
+            return Vec::new();
+        };
+
+        let factors = means
+            .iter()
+            .map(|&mean| if mean > 0. { median / mean } else { 1. })
+            .collect::<Vec<_>>();
+
+        self.images
+            .par_iter_mut()
+            .zip(&factors)
+            .filter(|(_, &factor)| factor != 1.)
+            .for_each(|(input, &factor)| {
+                let mut rgb = input.im.to_rgb8();
+                for p in rgb.pixels_mut() {
+                    for c in 0..3 {
+                        p[c] = (p[c] as f64 * factor).round().min(255.) as u8;
+                    }
+                }
+                input.im = DynamicImage::ImageRgb8(rgb);
+            });
+
+        factors
+    }
+
+    /// Applies a Gaussian blur with standard deviation `sigma` to every mask, feathering hard
+    /// seams. This should happen before `normalize_masks` so the overlaps renormalize.
+    pub fn blur_masks(&mut self, sigma: f32) {
+        self.masks = self.masks.par_iter().map(|m| m.blur(sigma)).collect();
+    }
+
+    /// Reverses which mask is assigned to which image, so that the first image receives the last
+    /// mask and vice versa.
+    pub fn reverse_masks(&mut self) {
+        self.masks.reverse();
+    }
+
+    /// Adds whatever weight the masks leave unassigned at each pixel, `255` minus their sum per
+    /// channel, to mask `index`. Pixels no mask covers then show that image instead of black.
+    pub fn fill_with_background(&mut self, index: usize) {
+        assert!(index < self.masks.len(), "Invalid mask index");
+
+        let (width, height) = (self.width, self.height);
+        let masks = &self.masks;
+        let residuals = (0..height)
+            .into_par_iter()
+            .flat_map_iter(|y| {
+                (0..width).map(move |x| {
+                    let mut sums = [0u32; 3];
+                    for mask in masks {
+                        let value = mask.get_pixel(x, y).to_rgb().0;
+                        for c in 0..3 {
+                            sums[c] += value[c] as u32;
+                        }
+                    }
+                    sums.map(|sum| 255u32.saturating_sub(sum))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut background = self.masks[index].to_rgb8();
+        for (pixel, residual) in background.pixels_mut().zip(residuals) {
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as u32 + residual[c]).min(255) as u8;
+            }
+        }
+        self.masks[index] = DynamicImage::ImageRgb8(background);
+    }
+
+    /// Ramps every mask down to zero weight over the `margin` pixels nearest the image's left and
+    /// right edges, or its top and bottom edges if `horizontal_bands`, so that the blend fades to
+    /// black at the ends of the sequence. This should follow normalization, which would undo it.
+    pub fn fade_edges(&mut self, margin: u32, horizontal_bands: bool) {
+        let (width, height) = (self.width, self.height);
+        let scale = |x: u32, y: u32| {
+            let (position, length) = match horizontal_bands {
+                true => (y, height),
+                false => (x, width),
+            };
+            let from_edge = position.min(length - 1 - position) as f64 + 0.5;
+            (from_edge / margin.max(1) as f64).min(1.)
+        };
+
+        self.masks.par_iter_mut().for_each(|mask| match mask {
+            DynamicImage::ImageLuma8(buf) => {
+                for (x, y, p) in buf.enumerate_pixels_mut() {
+                    p[0] = (p[0] as f64 * scale(x, y)).round() as u8;
+                }
+            }
+            _ => {
+                let mut buf = mask.to_rgb8();
+                for (x, y, p) in buf.enumerate_pixels_mut() {
+                    let scale = scale(x, y);
+                    for c in 0..3 {
+                        p[c] = (p[c] as f64 * scale).round() as u8;
+                    }
+                }
+                *mask = DynamicImage::ImageRgb8(buf);
+            }
+        });
+    }
+
+    /// Multiplies every mask by the luminance of `map`, scaled to `[0, 1]`, so that the weights
+    /// assigned in regions the map marks as important are kept while the rest fade. This should
+    /// precede normalization. `map` must have the images' dimensions.
+    pub fn apply_importance_map(&mut self, map: DynamicImage) -> Result<(), HdtrError> {
+        let expected = (self.width, self.height);
+        let received = map.dimensions();
+        if expected != received {
+            return Err(HdtrError::DimensionMismatch {
+                expected,
+                received,
+                details: "Importance map has different dimensions than the images".into(),
+            });
+        }
+
+        let map = map.to_luma8();
+        let scale =
+            |v: u8, x: u32, y: u32| (v as f64 * map.get_pixel(x, y)[0] as f64 / 255.).round() as u8;
+        self.masks.par_iter_mut().for_each(|mask| match mask {
+            DynamicImage::ImageLuma8(buf) => {
+                for (x, y, p) in buf.enumerate_pixels_mut() {
+                    p[0] = scale(p[0], x, y);
+                }
+            }
+            _ => {
+                let mut buf = mask.to_rgb8();
+                for (x, y, p) in buf.enumerate_pixels_mut() {
+                    for c in 0..3 {
+                        p[c] = scale(p[c], x, y);
+                    }
+                }
+                *mask = DynamicImage::ImageRgb8(buf);
+            }
+        });
+        Ok(())
+    }
+
+    /// Replaces mask `index` with its complement, mapping each value `v` to `255 - v`, so that
+    /// its image covers the region it previously didn't.
+    pub fn invert_mask(&mut self, index: usize) {
+        assert!(index < self.masks.len(), "Invalid mask index");
+        self.masks[index].invert();
+    }
+
+    /// Inverts every mask. See `invert_mask`.
+    pub fn invert_all_masks(&mut self) {
+        self.masks.par_iter_mut().for_each(|m| m.invert());
+    }
+
+    pub fn set_mask(&mut self, index: usize, mask: DynamicImage) {
+        assert!(index < self.masks.len(), "Invalid mask index");
+        assert_eq!(self.width, mask.width());
+        assert_eq!(self.height, mask.height());
+
+        self.masks[index] = mask;
+    }
+
+    /// Replaces every mask with one of full weight inside that image's `bands` and none elsewhere.
+    /// Bands are clipped to the image, and images without any get an empty mask.
+    pub fn set_band_masks(&mut self, bands: &[BandSpec]) {
+        let (width, height) = (self.width, self.height);
+        self.masks = (0..self.images.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut mask = GrayImage::new(width, height);
+                for band in bands.iter().filter(|b| b.image == i) {
+                    let (x0, x1) = (band.x0.min(width), band.x1.min(width));
+                    let y0 = band.y0.unwrap_or(0).min(height);
+                    let y1 = band.y1.unwrap_or(height).min(height);
+                    for y in y0..y1 {
+                        for x in x0..x1 {
+                            mask.put_pixel(x, y, Luma([255]));
+                        }
+                    }
+                }
+                DynamicImage::ImageLuma8(mask)
+            })
+            .collect();
+    }
+
+    /// Replaces every image's mask with one generated by `mask_type`, as a pipeline's
+    /// `generate_masks` does. The masks can then be modified, normalized or saved.
+    pub fn generate(&mut self, mask_type: MaskType) {
+        let mask_types = vec![mask_type; self.images.len()];
+        self.generate_masks(&mask_types, false);
+    }
+
+    /// Generates each mask `i` using `mask_types[i]`. With `antialias`, flat masks give pixels
+    /// that a band's edge passes through partial coverage rather than all or nothing.
+    pub(crate) fn generate_masks(&mut self, mask_types: &[MaskType], antialias: bool) {
+        assert_eq!(self.images.len(), mask_types.len());
+        let indexes = (0..self.images.len()).collect::<Vec<_>>();
+
+        let mut masks = indexes
+            .into_par_iter()
+            .map(|i| (i, self.generate_mask(i, mask_types[i], antialias)))
+            .collect::<Vec<_>>();
+        masks.sort_by_key(|(idx, _)| *idx);
+
+        self.masks = masks.into_iter().map(|(_, m)| m).collect();
+    }
+
+    fn generate_mask(
+        &self,
+        image_num: usize,
+        mask_type: MaskType,
+        antialias: bool,
+    ) -> DynamicImage {
+        // Generated masks are the same in every channel, so only one is stored.
+        let canvas = GrayImage::from_fn(self.width, self.height, |x, y| {
+            Luma([self.mask_weight(image_num, mask_type, x, y, antialias)])
+        });
+
+        DynamicImage::ImageLuma8(canvas)
+    }
+
+    /// Computes the value that `generate_mask` would write for image `image_num` at `(x, y)`.
+    fn mask_weight(
+        &self,
+        image_num: usize,
+        mask_type: MaskType,
+        x: u32,
+        y: u32,
+        antialias: bool,
+    ) -> u8 {
+        // The precise width/height (with fractional part) of each band. This avoids accumulating
+        // remainders that aren't handled.
+        let width_f = self.width as f64 / self.images.len() as f64;
+        let height_f = self.height as f64 / self.images.len() as f64;
+
+        match mask_type {
+            MaskType::VerticalFlat if antialias => band_coverage(
+                x,
+                width_f * image_num as f64,
+                width_f * (image_num + 1) as f64,
+            ),
+            MaskType::HorizontalFlat if antialias => band_coverage(
+                y,
+                height_f * image_num as f64,
+                height_f * (image_num + 1) as f64,
+            ),
+            MaskType::VerticalFlat => {
+                let x_start = band_edge(image_num, self.images.len(), self.width);
+                let x_end = band_edge(image_num + 1, self.images.len(), self.width);
+
+                if (x_start..x_end).contains(&x) {
+                    255
+                } else {
+                    0
+                }
+            }
+            MaskType::VerticalFlatFeather { overlap } => {
+                let x_start = width_f * image_num as f64;
+                let x_end = width_f * (image_num + 1) as f64;
+                let feather = overlap * width_f;
+
+                // Ramps from 0 to 1 across the `2 * feather` pixels centered on an edge, given
+                // the distance of this pixel's center inside that edge.
+                let ramp = |inside: f64| {
+                    if feather > 0. {
+                        ((inside + feather) / (2. * feather)).clamp(0., 1.)
+                    } else if inside >= 0. {
+                        1.
+                    } else {
+                        0.
+                    }
+                };
+
+                // The image's own edges aren't shared with a neighbor, so they aren't feathered.
+                let left = if image_num == 0 {
+                    1.
+                } else {
+                    ramp(x as f64 + 0.5 - x_start)
+                };
+                let right = if image_num + 1 == self.images.len() {
+                    1.
+                } else {
+                    ramp(x_end - (x as f64 + 0.5))
+                };
+
+                (left.min(right) * 255.).round() as u8
+            }
+            MaskType::HorizontalFlat => {
+                // Similar to above but with banded height
+                let y_start = band_edge(image_num, self.images.len(), self.height);
+                let y_end = band_edge(image_num + 1, self.images.len(), self.height);
+
+                if (y_start..y_end).contains(&y) {
+                    255
+                } else {
+                    0
+                }
+            }
+            MaskType::VerticalLogistic { k } => {
+                // Where should the most intense part be?
+                let center_x = image_num as f64 * width_f + width_f / 2.;
+
+                // Get the absolute distance from the center of this slice
+                let distance_x = (x as f64 - center_x).abs();
+
+                let logit = logistic(distance_x, k * width_f);
+                ((1. - logit) * 255.) as u8
+            }
+            MaskType::VerticalLogisticDithered { k, amplitude } => {
+                let center_x = image_num as f64 * width_f + width_f / 2.;
+                let distance_x = (x as f64 - center_x).abs();
+
+                let logit = logistic(distance_x, k * width_f);
+                let jitter = amplitude * (2. * position_noise(x, y, image_num) - 1.);
+                ((1. - logit) * 255. + jitter).clamp(0., 255.) as u8
+            }
+            MaskType::VerticalLogisticWrap { k } => {
+                let center_x = image_num as f64 * width_f + width_f / 2.;
+
+                // Measure the distance around the horizontal wrap too, so the left and right
+                // edges blend into each other.
+                let distance_x = (x as f64 - center_x).abs();
+                let distance_x = distance_x.min(self.width as f64 - distance_x);
+
+                let logit = logistic(distance_x, k * width_f);
+                ((1. - logit) * 255.) as u8
+            }
+            MaskType::HorizontalLogistic { k } => {
+                // Where should the most intense part be?
+                let center_y = image_num as f64 * height_f + height_f / 2.;
+
+                // Get the absolute distance from the center of this slice
+                let distance_y = (y as f64 - center_y).abs();
+
+                let logit = logistic(distance_y, k * height_f);
+                ((1. - logit) * 255.) as u8
+            }
+            MaskType::VerticalSine {
+                k,
+                amplitude,
+                wavelength,
+            } => {
+                // Every band's center is shifted by the same amount for a given row, so the bands
+                // still tile the width and each row's offset averages out over a full wavelength.
+                let center_x = image_num as f64 * width_f + width_f / 2.;
+                let offset = amplitude * (2. * std::f64::consts::PI * y as f64 / wavelength).sin();
+
+                let distance_x = (x as f64 - (center_x + offset)).abs();
+
+                let logit = logistic(distance_x, k * width_f);
+                ((1. - logit) * 255.) as u8
+            }
+            MaskType::Grid { cols, rows } => {
+                // Which tile contains this pixel?
+                let tx = (x as u64 * cols as u64 / self.width as u64) as usize;
+                let ty = (y as u64 * rows as u64 / self.height as u64) as usize;
+
+                if (ty * cols as usize + tx) % self.images.len() == image_num {
+                    255
+                } else {
+                    0
+                }
+            }
+            MaskType::CornerWipe { k } => {
+                // Bands run along the L1 diagonal distance from the top-left corner, with each
+                // band centered within its share of the range like the other logistic masks.
+                let band = (self.width + self.height) as f64 / self.images.len() as f64;
+                let center = image_num as f64 * band + band / 2.;
+
+                let distance = ((x + y) as f64 - center).abs();
+
+                let logit = logistic(distance, k * band);
+                ((1. - logit) * 255.) as u8
+            }
+            MaskType::Angular { k } => {
+                let (dx, dy) = self.offset_from_center(x, y);
+
+                // Clockwise from 12 o'clock, since y increases downward
+                self.angular_weight(image_num, dx.atan2(-dy), k)
+            }
+            MaskType::Cross { k } => {
+                let (dx, dy) = self.offset_from_center(x, y);
+
+                // Bands span the distance from the center to an edge, along each axis.
+                let n = self.images.len() as f64;
+                let (band_x, band_y) = (self.width as f64 / 2. / n, self.height as f64 / 2. / n);
+                let center = image_num as f64 + 0.5;
+
+                let vertical = logistic((dx.abs() - center * band_x).abs(), k * band_x);
+                let horizontal = logistic((dy.abs() - center * band_y).abs(), k * band_y);
+                ((1. - vertical.min(horizontal)) * 255.) as u8
+            }
+            MaskType::RadialFlat => {
+                let (dx, dy) = self.offset_from_center(x, y);
+                let radius = dx.hypot(dy) / (self.width as f64 / 2.).hypot(self.height as f64 / 2.);
+
+                // Pixel centers never quite reach the corners, but clamp so rounding can't leave
+                // a corner uncovered.
+                let n = self.images.len();
+                let ring = ((radius * n as f64) as usize).min(n - 1);
+                if ring == image_num {
+                    255
+                } else {
+                    0
+                }
+            }
+            MaskType::Spiral { turns, k } => {
+                let (dx, dy) = self.offset_from_center(x, y);
+                let radius = dx.hypot(dy) / (self.width as f64 / 2.).hypot(self.height as f64 / 2.);
+
+                // Clockwise from 12 o'clock, since y increases downward
+                let angle = dx.atan2(-dy) + 2. * std::f64::consts::PI * turns * radius;
+                self.angular_weight(image_num, angle, k)
+            }
+        }
+    }
+
+    /// The offset of pixel `(x, y)`'s center from the center of the image.
+    fn offset_from_center(&self, x: u32, y: u32) -> (f64, f64) {
+        (
+            x as f64 + 0.5 - self.width as f64 / 2.,
+            y as f64 + 0.5 - self.height as f64 / 2.,
+        )
+    }
+
+    /// The weight of image `image_num`'s wedge at `angle` radians, for masks that divide a full
+    /// turn into one logistic band per image.
+    fn angular_weight(&self, image_num: usize, angle: f64, k: f64) -> u8 {
+        // Angles are measured as lengths along the image's perimeter so that `k` behaves as it
+        // does for the masks that band by pixels.
+        let perimeter = 2. * (self.width + self.height) as f64;
+        let position =
+            angle.rem_euclid(2. * std::f64::consts::PI) / (2. * std::f64::consts::PI) * perimeter;
+
+        let band = perimeter / self.images.len() as f64;
+        let center = image_num as f64 * band + band / 2.;
+
+        // The last wedge meets the first, so distances wrap around.
+        let distance = (position - center).abs();
+        let distance = distance.min(perimeter - distance);
+
+        let logit = logistic(distance, k * band);
+        ((1. - logit) * 255.) as u8
+    }
+
+    /// Blends the input images using masks of type `mask_type` that are computed on the fly
+    /// rather than stored, writing the result to `destination`.
+    ///
+    /// The output is built one row at a time, so peak memory is the input images plus the output
+    /// canvas -- `self.masks` is not read and may be empty. When `normalize` is set, each pixel's
+    /// weights are scaled to sum to one, equivalent to calling `normalize_masks` first.
+    pub fn save_streaming<P: AsRef<Path>>(
+        &self,
+        destination: P,
+        mask_type: MaskType,
+        normalize: bool,
+    ) -> Result<(), HdtrError> {
+        let format = format_from_path(destination.as_ref())?;
+        self.blend_streaming(mask_type, normalize)
+            .save_with_format(destination, format)?;
+
+        Ok(())
+    }
+
+    /// The blend performed by `save_streaming`, returned rather than saved.
+    pub fn blend_streaming(&self, mask_type: MaskType, normalize: bool) -> RgbImage {
+        self.blend_band_masks_streaming(&vec![mask_type; self.images.len()], normalize, false)
+    }
+
+    /// Like `blend_streaming`, but image `i` is weighted by a mask of type `mask_types[i]`.
+    /// `antialias` is as for `generate_masks`.
+    pub(crate) fn blend_band_masks_streaming(
+        &self,
+        mask_types: &[MaskType],
+        normalize: bool,
+        antialias: bool,
+    ) -> RgbImage {
+        assert_eq!(self.images.len(), mask_types.len());
+        let masks = GeneratedMasks {
+            mask_types,
+            normalize,
+            antialias,
+        };
+        let rows = (0..self.height)
+            .into_par_iter()
+            .map(|y| {
+                let mut weights = vec![0.; self.images.len()];
+                (0..self.width)
+                    .map(|x| self.generated_blend_pixel(&masks, x, y, 0, &mut weights))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let mut canvas = RgbImage::new(self.width, self.height);
+        for (y, pxs) in rows.into_iter().enumerate() {
+            for (x, p) in pxs.into_iter().enumerate() {
+                canvas.put_pixel(x as u32, y as u32, p);
+            }
+        }
+
+        canvas
+    }
+
+    /// Blends pixel `(x, y)` with generated masks, without storing them. The images may be strips
+    /// starting at row `strip_y` of the output, and `weights` is scratch space for each image's
+    /// weight.
+    fn generated_blend_pixel(
+        &self,
+        masks: &GeneratedMasks,
+        x: u32,
+        y: u32,
+        strip_y: u32,
+        weights: &mut [f64],
+    ) -> image::Rgb<u8> {
+        for (i, w) in weights.iter_mut().enumerate() {
+            *w = self.mask_weight(i, masks.mask_types[i], x, y, masks.antialias) as f64 / 255.;
+        }
+
+        if masks.normalize {
+            let sum = weights.iter().sum::<f64>();
+            if sum > 0. {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            }
+        }
+
+        let (mut r_out, mut g_out, mut b_out) = (0., 0., 0.);
+        for (im, w) in self.images.iter().zip(weights.iter()) {
+            let p = im.im.get_pixel(x, y - strip_y).to_rgb();
+            r_out += p[0] as f64 * w;
+            g_out += p[1] as f64 * w;
+            b_out += p[2] as f64 * w;
+        }
+
+        image::Rgb([r_out as u8, g_out as u8, b_out as u8])
+    }
+
+    pub fn create_masks<F>(&mut self, f: F)
+    where
+        F: Fn(usize, u32, u32) -> u8,
+    {
+        self.create_masks_rgb(|i, x, y| {
+            let p = f(i, x, y);
+            [p, p, p]
+        });
+    }
+
+    /// Like `create_masks`, but `f` gives separate red, green and blue weights, which `save`
+    /// applies to the corresponding channels of the image.
+    pub fn create_masks_rgb<F>(&mut self, f: F)
+    where
+        F: Fn(usize, u32, u32) -> [u8; 3],
+    {
+        for i in 0..self.masks.len() {
+            self.create_mask_rgb(i, |x, y| f(i, x, y));
+        }
+    }
+
+    pub fn create_mask<F>(&mut self, index: usize, f: F)
+    where
+        F: Fn(u32, u32) -> u8,
+    {
+        self.create_mask_rgb(index, |x, y| {
+            let p = f(x, y);
+            [p, p, p]
+        });
+    }
+
+    /// Like `create_mask`, but `f` gives separate red, green and blue weights.
+    pub fn create_mask_rgb<F>(&mut self, index: usize, f: F)
+    where
+        F: Fn(u32, u32) -> [u8; 3],
+    {
+        assert!(index < self.masks.len(), "Invalid mask index");
+        let mut canvas = RgbImage::new(self.width, self.height);
+
+        for x in 0..self.width {
+            for y in 0..self.height {
+                let slice = f(x, y);
+                let p = Pixel::from_slice(&slice[..]);
+
+                canvas.put_pixel(x, y, *p);
+            }
+        }
+
+        self.masks[index] = DynamicImage::ImageRgb8(canvas);
+    }
+}
+
+impl IntoIterator for InputImages {
+    type Item = InputImage;
+    type IntoIter = std::vec::IntoIter<InputImage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.images.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a InputImages {
+    type Item = &'a InputImage;
+    type IntoIter = std::slice::Iter<'a, InputImage>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.images.iter()
+    }
+}
+
+/// The default search radius used when aligning images.
+pub const ALIGN_SEARCH_RADIUS: u32 = 16;
+
+/// Finds the `(dx, dy)`, each within `search`, for which `image(x + dx, y + dy)` best matches
+/// `reference(x, y)`, by minimizing the mean absolute difference over a grid of sample points.
+fn estimate_offset(
+    reference: &image::GrayImage,
+    image: &image::GrayImage,
+    search: u32,
+) -> (i32, i32) {
+    // Roughly this many points are compared for each candidate offset.
+    const SAMPLES: u64 = 250_000;
+
+    let (w, h) = reference.dimensions();
+    let s = search
+        .min(w.saturating_sub(1) / 2)
+        .min(h.saturating_sub(1) / 2) as i32;
+    let area = (w as u64) * (h as u64);
+    let step = ((area / SAMPLES) as f64).sqrt().max(1.) as usize;
+
+    let candidates = (-s..=s)
+        .flat_map(|dy| (-s..=s).map(move |dx| (dx, dy)))
+        .collect::<Vec<_>>();
+
+    let score = |(dx, dy): (i32, i32)| {
+        let (mut total, mut count) = (0u64, 0u64);
+        for y in (s as u32..h - s as u32).step_by(step) {
+            for x in (s as u32..w - s as u32).step_by(step) {
+                let a = reference.get_pixel(x, y)[0];
+                let b = image.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32)[0];
+                total += a.abs_diff(b) as u64;
+                count += 1;
+            }
+        }
+        total as f64 / count.max(1) as f64
+    };
+
+    // Prefer the smallest shift among equally good candidates.
+    candidates
+        .into_par_iter()
+        .map(|offset| (score(offset), offset.0.abs() + offset.1.abs(), offset))
+        .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+        .map(|(_, _, offset)| offset)
+        .unwrap_or((0, 0))
+}
+
+/// Translates `img` so that output pixel `(x, y)` is input pixel `(x + dx, y + dy)`, repeating
+/// edge pixels where that falls outside the image.
+fn shift_image(img: &DynamicImage, offset: (i32, i32)) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(b) => DynamicImage::ImageLuma8(shift_buffer(b, offset)),
+        DynamicImage::ImageRgb8(b) => DynamicImage::ImageRgb8(shift_buffer(b, offset)),
+        DynamicImage::ImageRgba8(b) => DynamicImage::ImageRgba8(shift_buffer(b, offset)),
+        DynamicImage::ImageLuma16(b) => DynamicImage::ImageLuma16(shift_buffer(b, offset)),
+        DynamicImage::ImageRgb16(b) => DynamicImage::ImageRgb16(shift_buffer(b, offset)),
+        DynamicImage::ImageRgba16(b) => DynamicImage::ImageRgba16(shift_buffer(b, offset)),
+        _ => DynamicImage::ImageRgba32F(shift_buffer(&img.to_rgba32f(), offset)),
+    }
+}
+
+fn shift_buffer<P: Pixel>(
+    buffer: &ImageBuffer<P, Vec<P::Subpixel>>,
+    (dx, dy): (i32, i32),
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    let (w, h) = buffer.dimensions();
+    ImageBuffer::from_fn(w, h, |x, y| {
+        let sx = (x as i64 + dx as i64).clamp(0, w as i64 - 1) as u32;
+        let sy = (y as i64 + dy as i64).clamp(0, h as i64 - 1) as u32;
+        *buffer.get_pixel(sx, sy)
+    })
+}
+
+/// The W3C soft-light blend of `source` over `backdrop`, both in `[0, 1]`.
+fn soft_light(backdrop: f64, source: f64) -> f64 {
+    if source <= 0.5 {
+        backdrop - (1. - 2. * source) * backdrop * (1. - backdrop)
+    } else {
+        let d = if backdrop <= 0.25 {
+            ((16. * backdrop - 12.) * backdrop + 4.) * backdrop
+        } else {
+            backdrop.sqrt()
+        };
+        backdrop + (2. * source - 1.) * (d - backdrop)
+    }
+}
+
+/// The weight `mask` gives pixel `(x, y)`, averaged over its channels, for views that show a
+/// single weight per image even when masks weight each channel separately.
+fn mean_weight(mask: &DynamicImage, x: u32, y: u32) -> f64 {
+    mask.get_pixel(x, y)
+        .to_rgb()
+        .0
+        .iter()
+        .map(|&c| c as f64)
+        .sum::<f64>()
+        / 3.
+}
+
+/// Converts a fully saturated, full-value hue in `[0, 1)` to RGB components in `[0, 1]`.
+fn hue_to_rgb(hue: f64) -> [f64; 3] {
+    let h = hue.rem_euclid(1.) * 6.;
+    let x = 1. - (h % 2. - 1.).abs();
+
+    match h as u32 {
+        0 => [1., x, 0.],
+        1 => [x, 1., 0.],
+        2 => [0., 1., x],
+        3 => [0., x, 1.],
+        4 => [x, 0., 1.],
+        _ => [1., 0., x],
+    }
+}
+
+/// Applies a gamma to each channel of `canvas`: `255 * (value / 255) ^ (1 / gamma[c])`.
+pub fn apply_gamma(canvas: &mut RgbImage, gamma: [f64; 3]) {
+    let tables = gamma.map(|g| {
+        let mut table = [0u8; 256];
+        for (v, out) in table.iter_mut().enumerate() {
+            *out = (255. * (v as f64 / 255.).powf(1. / g)).round() as u8;
+        }
+        table
+    });
+
+    canvas.par_chunks_mut(3).for_each(|p| {
+        for c in 0..3 {
+            p[c] = tables[c][p[c] as usize];
+        }
+    });
+}
+
+/// Decodes every page of a multi-page TIFF, in order.
+pub fn read_tiff_pages<P: AsRef<Path>>(path: P) -> Result<Vec<DynamicImage>, HdtrError> {
+    let path = path.as_ref();
+    let read_error =
+        |e: TiffError| HdtrError::HDTR(format!("Couldn't read {}: {e}", path.display()).into());
+
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut decoder = TiffDecoder::new(file).map_err(read_error)?;
+
+    let mut pages = Vec::new();
+    loop {
+        let (w, h) = decoder.dimensions().map_err(read_error)?;
+        let color = decoder.colortype().map_err(read_error)?;
+        let page = match (decoder.read_image().map_err(read_error)?, color) {
+            (DecodingResult::U8(buf), TiffColor::Gray(8)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLuma8)
+            }
+            (DecodingResult::U8(buf), TiffColor::GrayA(8)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLumaA8)
+            }
+            (DecodingResult::U8(buf), TiffColor::RGB(8)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb8)
+            }
+            (DecodingResult::U8(buf), TiffColor::RGBA(8)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgba8)
+            }
+            (DecodingResult::U16(buf), TiffColor::Gray(16)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLuma16)
+            }
+            (DecodingResult::U16(buf), TiffColor::GrayA(16)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageLumaA16)
+            }
+            (DecodingResult::U16(buf), TiffColor::RGB(16)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgb16)
+            }
+            (DecodingResult::U16(buf), TiffColor::RGBA(16)) => {
+                ImageBuffer::from_raw(w, h, buf).map(DynamicImage::ImageRgba16)
+            }
+            (_, color) => {
+                return Err(HdtrError::HDTR(
+                    format!(
+                        "Page {} of {} has unsupported color type {color:?}",
+                        pages.len(),
+                        path.display()
+                    )
+                    .into(),
+                ))
+            }
+        };
+
+        pages.push(page.ok_or_else(|| {
+            HdtrError::HDTR(
+                format!("Page {} of {} is truncated", pages.len(), path.display()).into(),
+            )
+        })?);
+
+        if !decoder.more_images() {
+            return Ok(pages);
+        }
+        decoder.next_image().map_err(read_error)?;
+    }
+}
+
+/// Whether `source` is an `http://` or `https://` URL rather than a file path.
+pub(crate) fn is_url(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Fetches `url` into memory and decodes it as an image.
+pub(crate) fn download_image(url: &str) -> Result<DynamicImage, HdtrError> {
+    let mut bytes = Vec::new();
+    ureq::get(url)
+        .call()?
+        .into_reader()
+        .read_to_end(&mut bytes)?;
+
+    Ok(image::load_from_memory(&bytes)?)
+}
+
+/// Opens the image at `source`, which may be a file path or a URL.
+pub(crate) fn open_image(source: &str) -> Result<DynamicImage, HdtrError> {
+    if is_url(source) {
+        download_image(source)
+    } else {
+        Ok(image::open(source)?)
+    }
+}
+
+/// Appended to an image's file stem to name its mask file.
+pub const DEFAULT_MASK_SUFFIX: &str = "_mask";
+
+/// Determines the output format from `path`'s extension.
+pub(crate) fn format_from_path(path: &Path) -> Result<ImageFormat, HdtrError> {
+    ImageFormat::from_path(path)
+        .map_err(|_| HdtrError::UnknownOutputFormat(path.display().to_string()))
+}
+
+pub(crate) fn write_jpeg<P: PixelWithColorType<Subpixel = u8>>(
+    canvas: &ImageBuffer<P, Vec<u8>>,
+    destination: &Path,
+    quality: u8,
+) -> Result<(), HdtrError> {
+    let file = std::io::BufWriter::new(std::fs::File::create(destination)?);
+    JpegEncoder::new_with_quality(file, quality).encode_image(canvas)?;
+    Ok(())
+}
+
+pub(crate) fn write_png<P: PixelWithColorType<Subpixel = u8>>(
+    canvas: &ImageBuffer<P, Vec<u8>>,
+    destination: &Path,
+    compression: CompressionType,
+    filter: PngFilter,
+) -> Result<(), HdtrError> {
+    let file = std::io::BufWriter::new(std::fs::File::create(destination)?);
+    PngEncoder::new_with_quality(file, compression, filter).write_image(
+        canvas.as_raw(),
+        canvas.width(),
+        canvas.height(),
+        P::COLOR_TYPE,
+    )?;
+    Ok(())
+}
+
+/// Writes `canvas` as a lossy WebP.
+pub(crate) fn write_webp<P: PixelWithColorType<Subpixel = u8>>(
+    canvas: &ImageBuffer<P, Vec<u8>>,
+    destination: &Path,
+    quality: u8,
+) -> Result<(), HdtrError> {
+    let file = std::io::BufWriter::new(std::fs::File::create(destination)?);
+    // image 0.24 deprecates lossy WebP in favour of `new_lossless`, but has no other way to honour
+    // `quality`. Lossless output goes through `save_with_format` and doesn't reach here.
+    #[allow(deprecated)]
+    WebPEncoder::new_with_quality(file, WebPQuality::lossy(quality)).encode(
+        canvas.as_raw(),
+        canvas.width(),
+        canvas.height(),
+        P::COLOR_TYPE,
+    )?;
+    Ok(())
+}
+
+/// A 5x7 bitmap font for the digits 0 through 9. Each row is a byte whose five low bits are the
+/// row's pixels, most significant on the left.
+const DIGIT_FONT: [[u8; 7]; 10] = [
+    [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+    [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+    [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+    [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+    [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+    [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+    [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+    [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+    [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+    [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+];
+
+/// Draws `number` in white on a black box centered at `center`, with each font pixel drawn as a
+/// `scale`-by-`scale` square. Parts falling outside `canvas` are clipped.
+fn draw_number(canvas: &mut RgbImage, number: usize, center: (i64, i64), scale: u32) {
+    let digits = number
+        .to_string()
+        .bytes()
+        .map(|b| (b - b'0') as usize)
+        .collect::<Vec<_>>();
+    let scale = scale as i64;
+
+    // Each glyph is 5 cells wide plus a cell of spacing, and the box has a cell of padding.
+    let (cols, rows) = (digits.len() as i64 * 6 + 1, 9);
+    let left = center.0 - cols * scale / 2;
+    let top = center.1 - rows * scale / 2;
+
+    for row in 0..rows {
+        for col in 0..cols {
+            let (glyph_row, glyph_col) = (row - 1, col - 1);
+            let lit = (0..7).contains(&glyph_row)
+                && glyph_col >= 0
+                && glyph_col % 6 < 5
+                && (glyph_col / 6) < digits.len() as i64
+                && DIGIT_FONT[digits[(glyph_col / 6) as usize]][glyph_row as usize]
+                    & (0x10 >> (glyph_col % 6))
+                    != 0;
+            let color = image::Rgb(if lit { [255; 3] } else { [0; 3] });
+
+            for dy in 0..scale {
+                for dx in 0..scale {
+                    let (x, y) = (left + col * scale + dx, top + row * scale + dy);
+                    if x >= 0 && y >= 0 && x < canvas.width() as i64 && y < canvas.height() as i64 {
+                        canvas.put_pixel(x as u32, y as u32, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The width in pixels of the divider between the images saved by `save_comparison`.
+const COMPARISON_DIVIDER_WIDTH: u32 = 4;
+
+/// The default window radius used by `InputImages::focus_stack`.
+const FOCUS_STACK_RADIUS: u32 = 2;
+
+/// The mean squared magnitude of `img`'s luminance gradient, a global measure of sharpness.
+fn gradient_energy(img: &DynamicImage) -> f64 {
+    let gray = img.to_luma8();
+    let (w, h) = gray.dimensions();
+    if w < 2 || h < 2 {
+        return 0.;
+    }
+
+    let total = (0..h - 1)
+        .into_par_iter()
+        .map(|y| {
+            (0..w - 1)
+                .map(|x| {
+                    let p = gray.get_pixel(x, y)[0] as f64;
+                    let dx = gray.get_pixel(x + 1, y)[0] as f64 - p;
+                    let dy = gray.get_pixel(x, y + 1)[0] as f64 - p;
+                    dx * dx + dy * dy
+                })
+                .sum::<f64>()
+        })
+        .sum::<f64>();
+    total / ((w - 1) * (h - 1)) as f64
+}
+
+/// Computes the Laplacian magnitude of `img`'s luminance at every pixel, summed over a square
+/// window extending `radius` pixels in each direction. Values are in row-major order.
+fn local_contrast(img: &DynamicImage, radius: u32) -> Vec<f64> {
+    let gray = img.to_luma8();
+    let (w, h) = (gray.width() as i64, gray.height() as i64);
+    let at = |x: i64, y: i64| {
+        gray.get_pixel(x.clamp(0, w - 1) as u32, y.clamp(0, h - 1) as u32)[0] as f64
+    };
+
+    let laplacian = (0..h)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            (0..w).map(move |x| {
+                (4. * at(x, y) - at(x - 1, y) - at(x + 1, y) - at(x, y - 1) - at(x, y + 1)).abs()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // A summed-area table, padded with a leading row and column of zeros, gives each window's
+    // sum in constant time.
+    let (w, h, r) = (w as usize, h as usize, radius as usize);
+    let stride = w + 1;
+    let mut sat = vec![0.; stride * (h + 1)];
+    for y in 0..h {
+        for x in 0..w {
+            sat[(y + 1) * stride + x + 1] =
+                laplacian[y * w + x] + sat[y * stride + x + 1] + sat[(y + 1) * stride + x]
+                    - sat[y * stride + x];
+        }
+    }
+
+    (0..h)
+        .into_par_iter()
+        .flat_map_iter(|y| {
+            let sat = &sat;
+            let (y0, y1) = (y.saturating_sub(r), (y + r + 1).min(h));
+            (0..w).map(move |x| {
+                let (x0, x1) = (x.saturating_sub(r), (x + r + 1).min(w));
+                sat[y1 * stride + x1] - sat[y0 * stride + x1] - sat[y1 * stride + x0]
+                    + sat[y0 * stride + x0]
+            })
+        })
+        .collect()
+}
+
+/// Converts `img` to grayscale using Rec. 601 luma weights (0.299R + 0.587G + 0.114B), with the
+/// result replicated across all three channels.
+pub(crate) fn luminance_mask(img: &DynamicImage) -> DynamicImage {
+    let mut rgb = img.to_rgb8();
+
+    for p in rgb.pixels_mut() {
+        let luma = (0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64).round() as u8;
+        *p = *Pixel::from_slice(&[luma, luma, luma]);
+    }
+
+    DynamicImage::ImageRgb8(rgb)
+}
+
+/// The weight of each of `count` frames under `schedule`, summing to 1. Frame `i` sits at
+/// `(i + 0.5) / count` through the sequence, so no frame's weight is zero.
+fn dissolve_weights(count: usize, schedule: DissolveSchedule) -> Vec<f64> {
+    let weights = (0..count)
+        .map(|i| {
+            let t = (i as f64 + 0.5) / count as f64;
+            match schedule {
+                DissolveSchedule::Triangular => 1. - (2. * t - 1.).abs(),
+                DissolveSchedule::Linear => t,
+            }
+        })
+        .collect::<Vec<_>>();
+    let total = weights.iter().sum::<f64>();
+    weights.into_iter().map(|w| w / total).collect()
+}
+
+/// Reduces `img` to the single channel selected by `channel`.
+pub(crate) fn channel_mask(img: &DynamicImage, channel: MaskChannel) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let weights = match channel {
+        MaskChannel::Luminance => return luminance_mask(img),
+        MaskChannel::Red => img.to_rgb8().pixels().map(|p| p[0]).collect(),
+        MaskChannel::Alpha => img.to_rgba8().pixels().map(|p| p[3]).collect(),
+    };
+    let mask = GrayImage::from_raw(width, height, weights).expect("One weight per pixel");
+    DynamicImage::ImageLuma8(mask)
+}
+
+/// The first pixel of band `index` when `length` pixels are split into `count` flat bands, or
+/// `length` for `index == count`. Integer arithmetic makes each band end exactly where the next
+/// starts, with the last ending at `length`; computing `length / count * index` in floating point
+/// could fall just short and leave an uncovered column.
+fn band_edge(index: usize, count: usize, length: u32) -> u32 {
+    (index as u64 * length as u64 / count as u64) as u32
+}
+
+/// The number of evenly spaced positions across a pixel sampled by antialiased flat masks.
+const ANTIALIAS_SAMPLES: u32 = 4;
+
+/// The fraction, scaled to 255, of `ANTIALIAS_SAMPLES` positions across pixel `pos` that fall
+/// within the band `[start, end)`.
+fn band_coverage(pos: u32, start: f64, end: f64) -> u8 {
+    let inside = (0..ANTIALIAS_SAMPLES)
+        .filter(|j| {
+            let sample = pos as f64 + (*j as f64 + 0.5) / ANTIALIAS_SAMPLES as f64;
+            (start..end).contains(&sample)
+        })
+        .count() as u32;
+
+    (inside * 255 / ANTIALIAS_SAMPLES) as u8
+}
+
+/// A deterministic pseudo-random value in `[0, 1)` for pixel `(x, y)` of image `image_num`'s mask,
+/// so that neighboring masks don't share their noise.
+fn position_noise(x: u32, y: u32, image_num: usize) -> f64 {
+    // The SplitMix64 finalizer, which spreads neighboring inputs across the whole range. Each
+    // image steps the state by a different multiple of the golden-ratio increment.
+    let mut z = ((x as u64) << 32 | y as u64)
+        .wrapping_add((image_num as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The `k` giving logistic masks a crossfade `seam_px` pixels wide between bands `band_px` pixels
+/// wide, measured from where the outgoing image has 90% of the weight to where it has 10%.
+///
+/// Normalized, neighboring logistic masks split the weight as `1 / (1 + e^(2 k band_px u))` at `u`
+/// pixels past the seam, which falls from 90% to 10% over `ln(9) / (k band_px)` pixels. This is
+/// solved for `k`. It's approximate when the seam is a large fraction of the band. Masks are stored
+/// with 8 bits, so seams narrower than about a quarter of the band come out sharper: each mask
+/// truncates to zero before reaching the seam, leaving its neighbor nothing to fade against.
+pub fn k_for_seam_width(seam_px: f64, band_px: f64) -> f64 {
+    9f64.ln() / (seam_px * band_px)
+}
 
 /// `k` is the steepness and should probably be roughly 0.01.
 /// For larger values (eg, 0.1), the band drops off quickly, meaning we have a narrow slice.
@@ -343,3 +2807,891 @@ pub fn logistic(distance: f64, k: f64) -> f64 {
     let sup = -k * distance;
     1. / (sup.exp() + 1.)
 }
+
+#[test]
+fn test_k_for_seam_width() {
+    // Two 200px bands meeting at x = 200, with a 60px seam between them.
+    let k = k_for_seam_width(60., 200.);
+    let mut images = InputImages::synthetic(2, 400, 1);
+    images.generate_masks(&[MaskType::VerticalLogistic { k }; 2], false);
+    images.normalize_masks();
+
+    // Far from the seam both masks truncate to 0, so search from the first band's center.
+    let first_below = |level: f64| {
+        (100..400)
+            .find(|&x| (images.masks[0].get_pixel(x, 0).to_rgb()[0] as f64) < level * 255.)
+            .unwrap()
+    };
+    let seam = first_below(0.1) - first_below(0.9);
+    assert!((58..=62).contains(&seam), "seam is {seam}px wide");
+}
+
+#[test]
+fn test_reverse_masks_twice() {
+    let mut images = InputImages::synthetic(3, 6, 2);
+
+    let original = images.masks.clone();
+
+    images.reverse_masks();
+    assert_ne!(original, images.masks);
+
+    images.reverse_masks();
+    assert_eq!(original, images.masks);
+}
+
+#[test]
+fn test_invert_masks_twice() {
+    let mut images = InputImages::synthetic(3, 6, 2);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], false);
+
+    let original = images.masks.clone();
+
+    images.invert_mask(1);
+    assert_eq!(original[0], images.masks[0]);
+    assert_ne!(original[1], images.masks[1]);
+    images.invert_mask(1);
+    assert_eq!(original, images.masks);
+
+    images.invert_all_masks();
+    for (before, after) in original.iter().zip(&images.masks) {
+        let (before, after) = (before.to_rgb8(), after.to_rgb8());
+        for (b, a) in before.pixels().zip(after.pixels()) {
+            assert_eq!(a.0, b.0.map(|v| 255 - v));
+        }
+    }
+
+    images.invert_all_masks();
+    assert_eq!(original, images.masks);
+}
+
+#[test]
+fn test_mask_from_threshold() {
+    let mut images = InputImages::synthetic(2, 4, 1);
+    images.images[1].im =
+        DynamicImage::ImageLuma8(GrayImage::from_raw(4, 1, vec![0, 100, 200, 255]).unwrap());
+
+    images.mask_from_threshold(0, 1, 100, 200);
+    assert_eq!(images.masks[0].to_luma8().into_raw(), vec![0, 255, 255, 0]);
+
+    images.mask_from_threshold(1, 1, 201, 255);
+    assert_eq!(images.masks[1].to_luma8().into_raw(), vec![0, 0, 0, 255]);
+}
+
+#[test]
+fn test_channel_masks() {
+    let rgba = image::RgbaImage::from_raw(2, 1, vec![10, 20, 30, 40, 50, 60, 70, 80]).unwrap();
+    let img = DynamicImage::ImageRgba8(rgba);
+
+    let red = channel_mask(&img, MaskChannel::Red);
+    assert_eq!(red.to_luma8().into_raw(), vec![10, 50]);
+    let alpha = channel_mask(&img, MaskChannel::Alpha);
+    assert_eq!(alpha.to_luma8().into_raw(), vec![40, 80]);
+
+    // Masks without an alpha channel give full weight everywhere.
+    let opaque = channel_mask(&DynamicImage::new_rgb8(2, 1), MaskChannel::Alpha);
+    assert_eq!(opaque.to_luma8().into_raw(), vec![255, 255]);
+}
+
+#[test]
+fn test_empty_image() {
+    // PNM is one of the few formats that decodes a 0x0 image rather than rejecting it.
+    let dir = std::env::temp_dir();
+    let (empty, full) = (
+        dir.join("hdtr_test_empty.ppm"),
+        dir.join("hdtr_test_nonempty.ppm"),
+    );
+    RgbImage::new(0, 0).save(&empty).unwrap();
+    RgbImage::new(2, 2).save(&full).unwrap();
+
+    match InputImages::new(&[&full, &empty]) {
+        Err(HdtrError::EmptyImage(path)) => assert_eq!(path, empty),
+        other => panic!("Expected an empty image error, got {:?}", other.err()),
+    }
+
+    let pipeline = pipeline::Pipeline::builder()
+        .add_image(full.to_str().unwrap())
+        .add_image(empty.to_str().unwrap())
+        .generate(MaskType::VerticalFlat)
+        .output(dir.join("hdtr_test_empty_output.png").to_str().unwrap())
+        .build()
+        .unwrap();
+    assert!(matches!(pipeline.execute(), Err(HdtrError::EmptyImage(_))));
+
+    let input = InputImage {
+        path: "synthetic.png".into(),
+        im: DynamicImage::new_rgb8(0, 0),
+    };
+    assert!(matches!(
+        input.ensure_nonempty(),
+        Err(HdtrError::EmptyImage(_))
+    ));
+}
+
+#[test]
+fn test_flat_bands_are_contiguous() {
+    // Floating-point band edges used to leave the last column uncovered at widths such as 61
+    // with 7 images.
+    for count in 2..=8 {
+        for width in 1..=1000 {
+            let images = InputImages::synthetic(count, width, 1);
+            for x in 0..width {
+                let default = images.masks.iter().filter(|m| m.get_pixel(x, 0)[0] == 255);
+                let generated = (0..count)
+                    .filter(|&i| images.mask_weight(i, MaskType::VerticalFlat, x, 0, false) == 255);
+                assert_eq!(
+                    (default.count(), generated.count()),
+                    (1, 1),
+                    "{count} images, width {width}: column {x} should be covered exactly once"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_importance_map() {
+    let mut images = InputImages::synthetic(2, 4, 1);
+    images.generate_masks(&[MaskType::VerticalFlat; 2], false);
+
+    let map = GrayImage::from_raw(4, 1, vec![255, 0, 51, 255]).unwrap();
+    images
+        .apply_importance_map(DynamicImage::ImageLuma8(map))
+        .unwrap();
+    assert_eq!(images.masks[0].to_luma8().into_raw(), vec![255, 0, 0, 0]);
+    assert_eq!(images.masks[1].to_luma8().into_raw(), vec![0, 0, 51, 255]);
+
+    let wrong_size = DynamicImage::new_luma8(3, 1);
+    assert!(images.apply_importance_map(wrong_size).is_err());
+}
+
+#[test]
+fn test_vertical_flat_partitions_width() {
+    for (count, width) in [(3, 7), (4, 10), (5, 13), (1, 4)] {
+        let mut images = InputImages::synthetic(count, width, 3);
+        images.generate_masks(&vec![MaskType::VerticalFlat; count], false);
+
+        for x in 0..width {
+            for y in 0..3 {
+                let values = images
+                    .masks
+                    .iter()
+                    .map(|m| m.get_pixel(x, y).to_rgb().0)
+                    .collect::<Vec<_>>();
+                assert!(values.iter().all(|v| *v == [0; 3] || *v == [255; 3]));
+                assert_eq!(
+                    values.iter().filter(|v| **v == [255; 3]).count(),
+                    1,
+                    "{count} images, width {width}: ({x}, {y}) should be covered exactly once"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_normalized_masks_sum_to_255() {
+    let count = 4;
+    let mut images = InputImages::synthetic(count, 40, 5);
+    images.generate_masks(&[MaskType::VerticalLogistic { k: 0.01 }; 4], false);
+    images.normalize_masks();
+
+    for x in 0..40 {
+        for y in 0..5 {
+            for c in 0..3 {
+                let sum = images
+                    .masks
+                    .iter()
+                    .map(|m| m.get_pixel(x, y).to_rgb()[c] as usize)
+                    .sum::<usize>();
+                // Each mask's value is truncated, losing less than one level.
+                assert!(
+                    (255 - count..=255).contains(&sum),
+                    "({x}, {y}) sums to {sum}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_softmax_normalization() {
+    let normalized = |temperature| {
+        let mut images = InputImages::synthetic(2, 2, 1);
+        let mask =
+            |values: Vec<u8>| DynamicImage::ImageLuma8(GrayImage::from_raw(2, 1, values).unwrap());
+        images.set_mask(0, mask(vec![255, 100]));
+        images.set_mask(1, mask(vec![0, 100]));
+        images.normalize_masks_softmax(temperature);
+        images.masks
+    };
+
+    // A low temperature all but hands the pixel to the heavier mask, while a high one splits it
+    // nearly evenly. Equal masks always split evenly.
+    let sharp = normalized(0.05);
+    assert_eq!(sharp[0].get_pixel(0, 0)[0], 254);
+    assert_eq!(sharp[0].get_pixel(1, 0)[0], 127);
+
+    assert_eq!(normalized(100.)[0].get_pixel(0, 0)[0], 128);
+}
+
+#[test]
+fn test_dominant_image_at() {
+    let mut images = InputImages::synthetic(3, 9, 1);
+    assert_eq!(images.dominant_image_at(4, 0), 1);
+    assert_eq!(images.dominant_image_at(8, 0), 2);
+
+    images.set_mask(2, DynamicImage::new_luma8(9, 1));
+    images.set_mask(1, DynamicImage::new_luma8(9, 1));
+    assert_eq!(images.dominant_image_at(8, 0), 0);
+}
+
+#[test]
+fn test_save_tiled() {
+    let mut images = InputImages::synthetic(3, 10, 7);
+    images.generate_masks(&[MaskType::VerticalLogistic { k: 0.01 }; 3], false);
+    images.normalize_masks();
+
+    let dir = std::env::temp_dir().join("hdtr_test_tiles");
+    let manifest = images.save_tiled(&dir, 4).unwrap();
+    assert_eq!(manifest.tiles.len(), 3 * 2);
+    assert!(dir.join("manifest.json").exists());
+
+    // The tiles, including the smaller ones at the edges, reassemble into the full blend.
+    let mut assembled = RgbImage::new(10, 7);
+    for rect in &manifest.tiles {
+        let tile = image::open(dir.join(&rect.file)).unwrap().to_rgb8();
+        assert_eq!(tile.dimensions(), (rect.w, rect.h));
+        assembled.copy_from(&tile, rect.x, rect.y).unwrap();
+    }
+    assert_eq!(assembled, images.blend_to_image());
+    assert!(images.save_tiled(&dir, 0).is_err());
+}
+
+#[test]
+fn test_save_tiled_from_files() {
+    let dir = std::env::temp_dir();
+    let paths = (0..3)
+        .map(|i| {
+            let path = dir.join(format!("hdtr_test_tiled_input{i}.png"));
+            RgbImage::from_fn(10, 7, |x, y| {
+                image::Rgb([i * 80, x as u8 * 20, y as u8 * 30])
+            })
+            .save(&path)
+            .unwrap();
+            path
+        })
+        .collect::<Vec<_>>();
+    let mask_types = [MaskType::VerticalLogistic { k: 0.01 }; 3];
+
+    let tiles = dir.join("hdtr_test_tiles_from_files");
+    let manifest =
+        InputImages::save_tiled_from_files(&paths, &mask_types, true, &tiles, 4).unwrap();
+    assert_eq!(manifest.tiles.len(), 3 * 2);
+
+    // The tiles reassemble into the same blend as generating the masks over the whole images.
+    let mut assembled = RgbImage::new(10, 7);
+    for rect in &manifest.tiles {
+        let tile = image::open(tiles.join(&rect.file)).unwrap().to_rgb8();
+        assembled.copy_from(&tile, rect.x, rect.y).unwrap();
+    }
+    let images = InputImages::new(&paths).unwrap();
+    assert_eq!(
+        assembled,
+        images.blend_band_masks_streaming(&mask_types, true, false)
+    );
+
+    // Every input must match the first's size.
+    let small = dir.join("hdtr_test_tiled_small.png");
+    RgbImage::new(3, 3).save(&small).unwrap();
+    let mismatched = [paths[0].clone(), small];
+    assert!(matches!(
+        InputImages::save_tiled_from_files(&mismatched, &mask_types[..2], true, &tiles, 4),
+        Err(HdtrError::DimensionMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_mask_atlas() {
+    let mut images = InputImages::synthetic(3, 4, 2);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], false);
+
+    let dir = std::env::temp_dir();
+    let (image_dest, json_dest) = (
+        dir.join("hdtr_test_atlas.png"),
+        dir.join("hdtr_test_atlas.json"),
+    );
+    let rects = images.save_mask_atlas(&image_dest, &json_dest).unwrap();
+
+    // Three masks fit a 2x2 grid.
+    assert_eq!(
+        rects[2],
+        AtlasRect {
+            index: 2,
+            x: 0,
+            y: 2,
+            w: 4,
+            h: 2
+        }
+    );
+    let atlas = image::open(&image_dest).unwrap();
+    assert_eq!((atlas.dimensions(), atlas.color()), ((8, 4), ColorType::L8));
+    for rect in &rects {
+        let mask = &images.masks[rect.index];
+        for (x, y, p) in mask.pixels() {
+            assert_eq!(atlas.get_pixel(rect.x + x, rect.y + y), p);
+        }
+    }
+
+    let json = std::fs::read_to_string(&json_dest).unwrap();
+    assert!(json.starts_with(r#"[{"index":0,"x":0,"y":0,"w":4,"h":2}"#));
+}
+
+#[test]
+fn test_logistic_centers_are_sub_pixel() {
+    // Bands are 0.6 pixels wide, so rounding centers to whole pixels would give several images
+    // the same center and so the same mask.
+    let mut images = InputImages::synthetic(10, 6, 1);
+    images.generate_masks(&[MaskType::VerticalLogistic { k: 2. }; 10], false);
+
+    for (i, a) in images.masks.iter().enumerate() {
+        for b in &images.masks[i + 1..] {
+            assert_ne!(a.as_bytes(), b.as_bytes());
+        }
+    }
+
+    // Masks peak at the pixel nearest their center, which moves right with each image.
+    let peaks = images
+        .masks
+        .iter()
+        .map(|m| (0..6).max_by_key(|&x| m.get_pixel(x, 0)[0]).unwrap())
+        .collect::<Vec<_>>();
+    assert!(peaks.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+fn test_dithered_masks_have_independent_jitter() {
+    let jitter = |dithered: &DynamicImage, plain: &DynamicImage| {
+        dithered
+            .as_bytes()
+            .iter()
+            .zip(plain.as_bytes())
+            .map(|(&d, &p)| d as i16 - p as i16)
+            .collect::<Vec<_>>()
+    };
+
+    // A shallow k keeps the weights mid-range, so the jitter is never clamped away.
+    let mut plain = InputImages::synthetic(2, 16, 8);
+    plain.generate_masks(&[MaskType::VerticalLogistic { k: 0.01 }; 2], false);
+    let mut dithered = InputImages::synthetic(2, 16, 8);
+    dithered.generate_masks(
+        &[MaskType::VerticalLogisticDithered {
+            k: 0.01,
+            amplitude: 8.,
+        }; 2],
+        false,
+    );
+
+    let first = jitter(&dithered.masks[0], &plain.masks[0]);
+    let second = jitter(&dithered.masks[1], &plain.masks[1]);
+    assert!(first.iter().any(|&j| j != 0));
+    // Shared noise would only differ by truncation, which is at most one level.
+    assert!(first.iter().zip(&second).any(|(a, b)| (a - b).abs() > 1));
+}
+
+#[test]
+fn test_radial_flat_rings() {
+    let mut images = InputImages::synthetic(3, 31, 21);
+    images.generate_masks(&[MaskType::RadialFlat; 3], false);
+
+    for (x, y, _) in images.masks[0].pixels() {
+        let owners = images
+            .masks
+            .iter()
+            .filter(|m| m.get_pixel(x, y)[0] == 255)
+            .count();
+        assert_eq!(owners, 1, "({x}, {y}) should be in exactly one ring");
+    }
+
+    // The center is in the first ring and every corner in the last.
+    assert_eq!(images.masks[0].get_pixel(15, 10)[0], 255);
+    for (x, y) in [(0, 0), (30, 0), (0, 20), (30, 20)] {
+        assert_eq!(images.masks[2].get_pixel(x, y)[0], 255);
+    }
+}
+
+#[test]
+fn test_cross_masks_cover_image() {
+    let mut images = InputImages::synthetic(3, 30, 20);
+    images.generate_masks(&[MaskType::Cross { k: 0.05 }; 3], false);
+
+    // The first image dominates along the central row and column, the last near the corners.
+    let weight = |images: &InputImages, i: usize, x, y| images.masks[i].get_pixel(x, y)[0];
+    assert!(weight(&images, 0, 15, 1) > weight(&images, 1, 15, 1));
+    assert!(weight(&images, 0, 1, 10) > weight(&images, 2, 1, 10));
+    assert!(weight(&images, 2, 0, 0) > weight(&images, 0, 0, 0));
+
+    images.normalize_masks();
+    for (x, y, _) in images.images[0].im.pixels() {
+        let sum = (0..3).map(|i| weight(&images, i, x, y) as u32).sum::<u32>();
+        assert!(sum >= 252, "({x}, {y}) sums to {sum}");
+    }
+}
+
+#[test]
+fn test_parallel_mask_generation_matches_serial() {
+    let mut images = InputImages::synthetic(5, 23, 7);
+    let mask_types = [MaskType::VerticalLogistic { k: 0.02 }; 5];
+    let serial = (0..5)
+        .map(|i| images.generate_mask(i, mask_types[i], false))
+        .collect::<Vec<_>>();
+
+    images.generate_masks(&mask_types, false);
+    assert_eq!(images.masks, serial);
+}
+
+#[test]
+fn test_generated_masks_stay_single_channel() {
+    let mut images = InputImages::synthetic(3, 12, 2);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], false);
+    images.normalize_masks();
+    assert!(images.masks.iter().all(|m| m.color() == ColorType::L8));
+
+    // Mixing in a color mask normalizes them all in color.
+    images.set_mask(0, DynamicImage::new_rgb8(12, 2));
+    images.normalize_masks();
+    assert!(images.masks.iter().all(|m| m.color() == ColorType::Rgb8));
+}
+
+#[test]
+fn test_vertical_flat_feather_crossfades() {
+    let mut images = InputImages::synthetic(3, 30, 1);
+    images.generate_masks(&[MaskType::VerticalFlatFeather { overlap: 0.2 }; 3], false);
+
+    // Bands are 10 pixels wide, so each seam ramps over the 4 pixels centered on it.
+    let weights = |x| {
+        images
+            .masks
+            .iter()
+            .map(|m| m.get_pixel(x, 0)[0])
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(weights(0), [255, 0, 0]);
+    assert_eq!(weights(7), [255, 0, 0]);
+    assert_eq!(weights(9), [159, 96, 0]);
+    assert_eq!(weights(15), [0, 255, 0]);
+    assert_eq!(weights(29), [0, 0, 255]);
+
+    for x in 0..30 {
+        let sum = weights(x).iter().map(|&w| w as u32).sum::<u32>();
+        assert!((254..=256).contains(&sum), "{x} sums to {sum}");
+    }
+}
+
+#[test]
+fn test_accumulator_matches_blend() {
+    let mut images = InputImages::synthetic(4, 20, 3);
+    images.generate_masks(&[MaskType::VerticalLogistic { k: 0.01 }; 4], false);
+    images.normalize_masks();
+
+    let mut accumulator = Accumulator::new(20, 3);
+    for (input, mask) in images.images.iter().zip(&images.masks) {
+        accumulator.add_frame(&input.im, mask).unwrap();
+    }
+    let accumulated = accumulator.finalize();
+
+    // Normalized masks are truncated to whole levels, which the accumulator divides back out.
+    for (a, b) in accumulated.pixels().zip(images.blend_to_image().pixels()) {
+        for c in 0..3 {
+            assert!(a[c].abs_diff(b[c]) <= 3, "{a:?} vs {b:?}");
+        }
+    }
+}
+
+#[test]
+fn test_single_precision_blend() {
+    let mut images = InputImages::synthetic(5, 40, 3);
+    images.generate_masks(&[MaskType::VerticalLogistic { k: 0.005 }; 5], false);
+    images.normalize_masks();
+
+    let single = images.blend_to_image_with_precision(Precision::Single);
+    let double = images.blend_to_image_with_precision(Precision::Double);
+    assert_eq!(double, images.blend_to_image());
+    for (a, b) in single.pixels().zip(double.pixels()) {
+        for c in 0..3 {
+            assert!(a[c].abs_diff(b[c]) <= 1, "{a:?} vs {b:?}");
+        }
+    }
+}
+
+#[test]
+fn test_mask_paths() {
+    let mut images = InputImages::synthetic(3, 4, 4);
+    images.images[1].path = "frames/b.jpg".into();
+    images.images[2].path = "/".into();
+
+    assert!(matches!(
+        images.mask_paths(),
+        Err(HdtrError::InvalidImagePath(path)) if path == Path::new("/")
+    ));
+
+    images.images[2].path = "c".into();
+    assert_eq!(
+        images.mask_paths().unwrap(),
+        [
+            PathBuf::from("image0_mask.png"),
+            PathBuf::from("frames/b_mask.png"),
+            PathBuf::from("c_mask.png"),
+        ]
+    );
+}
+
+#[test]
+fn test_sharpness_masks_pick_textured_tiles() {
+    let mut images = InputImages::synthetic(2, 8, 4);
+    // Image 1 is checkered in its right half only, so it wins just those tiles.
+    images.images[1].im = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 4, |x, y| {
+        let v = if x >= 4 && (x + y) % 2 == 0 { 255 } else { 0 };
+        image::Rgb([v; 3])
+    }));
+
+    assert!(images.generate_sharpness_masks(0).is_err());
+    images.generate_sharpness_masks(4).unwrap();
+
+    for y in 0..4 {
+        for x in 0..8 {
+            let expected = if x >= 4 { [0, 255] } else { [255, 0] };
+            assert_eq!(images.masks[0].get_pixel(x, y)[0], expected[0]);
+            assert_eq!(images.masks[1].get_pixel(x, y)[0], expected[1]);
+        }
+    }
+}
+
+#[test]
+fn test_antialiased_flat_seams() {
+    // Bands are 10/3 pixels wide, so seams fall within pixels 3 and 6.
+    let mut images = InputImages::synthetic(3, 10, 1);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], true);
+
+    let weights = |x| {
+        images
+            .masks
+            .iter()
+            .map(|m| m.get_pixel(x, 0)[0])
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(weights(2), [255, 0, 0]);
+    assert_eq!(weights(3), [63, 191, 0]);
+    assert_eq!(weights(6), [0, 191, 63]);
+    assert_eq!(weights(9), [0, 0, 255]);
+}
+
+#[test]
+fn test_blend_multi_averages_composites() {
+    let mut images = InputImages::synthetic(2, 6, 4);
+    images.generate_masks(&[MaskType::VerticalFlat; 2], false);
+    let vertical = images.masks.clone();
+    let vertical_blend = images.blend_to_image();
+
+    images.generate_masks(&[MaskType::HorizontalFlat; 2], false);
+    let horizontal = images.masks.clone();
+    let horizontal_blend = images.blend_to_image();
+
+    let blended = images.blend_multi(&[vertical, horizontal]);
+    for ((p, v), h) in blended
+        .pixels()
+        .zip(vertical_blend.pixels())
+        .zip(horizontal_blend.pixels())
+    {
+        for c in 0..3 {
+            assert_eq!(p[c], ((v[c] as u32 + h[c] as u32) / 2) as u8);
+        }
+    }
+}
+
+#[test]
+fn test_save_comparison() {
+    let images = InputImages::synthetic(3, 10, 4);
+    let destination = std::env::temp_dir().join("hdtr_test_comparison.png");
+
+    assert!(images.save_comparison(&destination, 3).is_err());
+
+    images.save_comparison(&destination, 1).unwrap();
+    let comparison = image::open(&destination).unwrap();
+    assert_eq!(comparison.dimensions(), (24, 4));
+    assert_eq!(comparison.get_pixel(11, 0).to_rgb(), image::Rgb([255; 3]));
+    assert_eq!(
+        comparison.get_pixel(23, 3).to_rgb(),
+        images.images[1].im.get_pixel(9, 3).to_rgb()
+    );
+
+    images
+        .save_comparison_scaled(&destination, 1, Some(12))
+        .unwrap();
+    assert_eq!(image::open(&destination).unwrap().dimensions(), (12, 2));
+
+    std::fs::remove_file(destination).unwrap();
+}
+
+#[test]
+fn test_percentile_blend() {
+    let mut images = InputImages::synthetic(3, 2, 1);
+    for (input, v) in images.images.iter_mut().zip([30u8, 10, 20]) {
+        input.im = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 1, image::Rgb([v, 255 - v, v])));
+    }
+
+    assert_eq!(images.percentile_blend(0.).get_pixel(0, 0).0, [10, 225, 10]);
+    assert_eq!(
+        images.percentile_blend(0.5).get_pixel(0, 0).0,
+        [20, 235, 20]
+    );
+    assert_eq!(
+        images.percentile_blend(0.75).get_pixel(1, 0).0,
+        [25, 240, 25]
+    );
+    assert_eq!(images.percentile_blend(1.).get_pixel(1, 0).0, [30, 245, 30]);
+}
+
+#[test]
+fn test_difference_map() {
+    let mut images = InputImages::synthetic(3, 2, 1);
+    for (input, v) in images.images.iter_mut().zip([10u8, 50, 30]) {
+        input.im = DynamicImage::ImageRgb8(RgbImage::from_fn(2, 1, |x, _| {
+            image::Rgb([v * x as u8, 7, 255 - v])
+        }));
+    }
+
+    let map = images.difference_map();
+    assert_eq!(map.get_pixel(0, 0).0, [0, 0, 40]);
+    assert_eq!(map.get_pixel(1, 0).0, [40, 0, 40]);
+}
+
+#[test]
+fn test_time_dissolve() {
+    let mut images = InputImages::synthetic(3, 2, 1);
+    for (input, v) in images.images.iter_mut().zip([0u8, 90, 180]) {
+        input.im = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 1, image::Rgb([v; 3])));
+    }
+
+    // Triangular weights are 1/5, 3/5 and 1/5; linear ones 1/9, 3/9 and 5/9.
+    let triangular = images.time_dissolve(DissolveSchedule::Triangular);
+    assert_eq!(triangular.get_pixel(1, 0).0, [90; 3]);
+    let linear = images.time_dissolve(DissolveSchedule::Linear);
+    assert_eq!(linear.get_pixel(1, 0).0, [130; 3]);
+}
+
+#[test]
+fn test_motion_trail() {
+    let mut images = InputImages::synthetic(3, 3, 1);
+    for (i, input) in images.images.iter_mut().enumerate() {
+        // A bright dot moving right, one pixel per frame
+        let dot = RgbImage::from_fn(3, 1, |x, _| image::Rgb([(x as usize == i) as u8 * 200; 3]));
+        input.im = DynamicImage::ImageRgb8(dot);
+    }
+
+    // Weights are 0.25, 0.5 and 1 from the oldest frame to the newest.
+    let lighten = images.motion_trail(0.5, TrailCombine::Lighten);
+    assert_eq!(
+        lighten.pixels().map(|p| p[0]).collect::<Vec<_>>(),
+        [50, 100, 200]
+    );
+    let average = images.motion_trail(0.5, TrailCombine::Average);
+    assert_eq!(
+        average.pixels().map(|p| p[0]).collect::<Vec<_>>(),
+        [29, 57, 114]
+    );
+
+    // Without decay, lighten is an ordinary lighten blend.
+    let flat = images.motion_trail(1., TrailCombine::Lighten);
+    assert!(flat.pixels().all(|p| p.0 == [200; 3]));
+}
+
+#[test]
+fn test_blend_with_mask_fn() {
+    let mut images = InputImages::synthetic(3, 12, 2);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], false);
+
+    // Weights of 0 or 1 in each image's band match the stored flat masks.
+    let band = |i: usize, x: u32, _| ((x / 4) as usize == i) as u8 as f64;
+    assert_eq!(images.blend_with_mask_fn(band), images.blend_to_image());
+
+    // Weights are normalized, so scaling them all changes nothing.
+    let doubled = images.blend_with_mask_fn(|i, x, y| 2. * band(i, x, y));
+    assert_eq!(doubled, images.blend_to_image());
+
+    let black = images.blend_with_mask_fn(|_, _, _| 0.);
+    assert!(black.pixels().all(|p| p.0 == [0; 3]));
+}
+
+#[test]
+fn test_encode() {
+    let mut images = InputImages::synthetic(3, 12, 2);
+    images.generate_masks(&[MaskType::VerticalFlat; 3], false);
+
+    let png = images.encode(ImageFormat::Png).unwrap();
+    let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+    assert_eq!(decoded.to_rgb8(), images.blend_to_image());
+
+    let jpeg = images.encode(ImageFormat::Jpeg).unwrap();
+    assert_eq!(image::guess_format(&jpeg).unwrap(), ImageFormat::Jpeg);
+}
+
+#[test]
+fn test_16_bit_blend_keeps_levels() {
+    let mut images = InputImages::synthetic(2, 200, 1);
+    for (i, input) in images.images.iter_mut().enumerate() {
+        input.im = DynamicImage::ImageRgb16(Rgb16Image::from_fn(200, 1, |x, _| {
+            image::Rgb([(x * 10 + i as u32) as u16; 3])
+        }));
+    }
+    images.generate_masks(&[MaskType::VerticalFlat; 2], false);
+    assert!(images.is_high_bit_depth());
+
+    // The gradient spans only about 8 levels once truncated to 8 bits.
+    let levels = |values: Vec<u32>| {
+        values
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>()
+            .len()
+    };
+    let deep = levels(
+        images
+            .blend_to_image16()
+            .pixels()
+            .map(|p| p[0] as u32)
+            .collect(),
+    );
+    let shallow = levels(
+        images
+            .blend_to_image()
+            .pixels()
+            .map(|p| p[0] as u32)
+            .collect(),
+    );
+    assert!(deep > 10 * shallow, "{deep} vs {shallow} levels");
+}
+
+#[test]
+fn test_blend_subset() {
+    let mut images = InputImages::synthetic(4, 8, 1);
+    images.generate_masks(&[MaskType::VerticalFlat; 4], false);
+    let colors = images
+        .images
+        .iter()
+        .map(|input| input.im.get_pixel(0, 0).to_rgb())
+        .collect::<Vec<_>>();
+
+    // Each band shows its own image if selected and is black otherwise.
+    let subset = images.blend_subset(&[1, 3]).unwrap();
+    assert_eq!(*subset.get_pixel(0, 0), image::Rgb([0; 3]));
+    assert_eq!(*subset.get_pixel(2, 0), colors[1]);
+    assert_eq!(*subset.get_pixel(6, 0), colors[3]);
+
+    // Selecting every image matches the full blend.
+    assert_eq!(
+        images.blend_subset(&[0, 1, 2, 3]).unwrap(),
+        images.blend_to_image()
+    );
+
+    assert!(images.blend_subset(&[]).is_err());
+    assert!(images.blend_subset(&[1, 4]).is_err());
+}
+
+#[test]
+fn test_sigma_clip_blend() {
+    let mut images = InputImages::synthetic(5, 1, 1);
+    for (input, v) in images.images.iter_mut().zip([10u8, 12, 11, 9, 250]) {
+        input.im = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, image::Rgb([v; 3])));
+    }
+
+    // The plain mean is pulled up by the outlier; clipping discards it.
+    assert_eq!(images.sigma_clip_blend(1.5, 0).get_pixel(0, 0).0, [58; 3]);
+    assert_eq!(images.sigma_clip_blend(1.5, 2).get_pixel(0, 0).0, [11; 3]);
+
+    // Every value of two-valued data is a standard deviation from the mean, so a smaller sigma
+    // would clip them all; the mean is kept instead of going black.
+    images.images.pop();
+    for (input, v) in images.images.iter_mut().zip([0u8, 200, 0, 200]) {
+        input.im = DynamicImage::ImageRgb8(RgbImage::from_pixel(1, 1, image::Rgb([v; 3])));
+    }
+    assert_eq!(images.sigma_clip_blend(0.5, 3).get_pixel(0, 0).0, [100; 3]);
+}
+
+#[test]
+fn test_sharpness_weights() {
+    let mut images = InputImages::synthetic(2, 4, 4);
+    images.images[1].im = DynamicImage::ImageRgb8(RgbImage::from_fn(4, 4, |x, y| {
+        image::Rgb([if (x + y) % 2 == 0 { 0 } else { 200 }; 3])
+    }));
+
+    // The flat first image has no detail, so its mask is zeroed.
+    assert_eq!(images.apply_sharpness_weights(), vec![0., 1.]);
+    assert_eq!(images.masks[0].get_pixel(0, 0).to_rgb().0, [0; 3]);
+    assert_eq!(images.masks[1].get_pixel(3, 0).to_rgb().0, [255; 3]);
+}
+
+#[test]
+fn test_contribution_map_uses_every_channel() {
+    // The first mask weights only green and the second only red, so judging by red alone would
+    // give the second image every pixel.
+    let mut images = InputImages::synthetic(2, 2, 2);
+    images.masks = vec![
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([0, 255, 0]))),
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0]))),
+    ];
+
+    // Equal shares of red (image 0's hue) and cyan (image 1's) mix to gray.
+    let map = images.debug_contribution_map();
+    assert_eq!(map.get_pixel(0, 0), &image::Rgb([128, 128, 128]));
+}
+
+#[test]
+fn test_labels_follow_color_mask_weight() {
+    // Image 0's mask weights only blue, on the right, so its label belongs on the right too.
+    let mut images = InputImages::synthetic(2, 100, 20);
+    let right_blue = RgbImage::from_fn(100, 20, |x, _| image::Rgb([0, 0, (x >= 50) as u8 * 255]));
+    let left = RgbImage::from_fn(100, 20, |x, _| image::Rgb([(x < 50) as u8 * 255; 3]));
+    images.masks = vec![
+        DynamicImage::ImageRgb8(right_blue),
+        DynamicImage::ImageRgb8(left),
+    ];
+
+    let labeled = images.debug_contribution_map_labeled();
+    let unlabeled = images.debug_contribution_map();
+    let changed = |xs: std::ops::Range<u32>| {
+        xs.flat_map(|x| (0..20).map(move |y| (x, y)))
+            .any(|(x, y)| labeled.get_pixel(x, y) != unlabeled.get_pixel(x, y))
+    };
+    assert!(changed(50..100));
+    assert!(changed(0..50));
+}
+
+#[test]
+fn test_layers_stack_to_blend_with_color_masks() {
+    let mut images = InputImages::synthetic(3, 4, 1);
+    images.masks = [[200, 0, 90], [40, 255, 90], [100, 30, 0]]
+        .map(|rgb| DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 1, image::Rgb(rgb))))
+        .to_vec();
+    let dir = std::env::temp_dir().join("hdtr_test_color_layers");
+    images.save_layers(&dir).unwrap();
+
+    // Composite the layers with straight alpha, as an editor would.
+    let mut stacked = [0.; 3];
+    for i in 0..3 {
+        let layer = image::open(dir.join(format!("layer_{i}.png")))
+            .unwrap()
+            .to_rgba8();
+        let p = layer.get_pixel(0, 0);
+        let a = p[3] as f64 / 255.;
+        stacked = [0, 1, 2].map(|c| a * p[c] as f64 + (1. - a) * stacked[c]);
+    }
+
+    images.normalize_masks();
+    let blend = images.blend_to_image();
+    for c in 0..3 {
+        let expected = blend.get_pixel(0, 0)[c] as f64;
+        assert!(
+            (stacked[c] - expected).abs() <= 2.,
+            "channel {c}: {stacked:?} vs {expected}"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Reads 3D color lookup tables in the `.cube` format and applies them to images.
+
+use std::path::Path;
+
+use image::RgbImage;
+use rayon::prelude::*;
+
+use crate::HdtrError;
+
+/// A 3D color lookup table, mapping each input color to an output color by trilinear
+/// interpolation between its nearest entries.
+pub struct Lut {
+    /// The number of entries along each axis.
+    size: usize,
+    /// The entries, with red varying fastest and blue slowest, as in `.cube` files.
+    table: Vec<[f64; 3]>,
+    domain_min: [f64; 3],
+    domain_max: [f64; 3],
+}
+
+impl Lut {
+    /// Parses a `.cube` file with a `LUT_3D_SIZE`, such as the common 17- and 33-point LUTs.
+    pub fn from_cube<P: AsRef<Path>>(path: P) -> Result<Self, HdtrError> {
+        let path = path.as_ref();
+        let invalid = |details: String| HdtrError::InvalidLut {
+            path: path.into(),
+            details: details.into(),
+        };
+
+        let text = std::fs::read_to_string(path)?;
+        let mut size = None;
+        let mut domain_min = [0.; 3];
+        let mut domain_max = [1.; 3];
+        let mut table = Vec::new();
+
+        for (num, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let keyword = fields.next().unwrap_or_default();
+            let triple = |fields: std::str::SplitWhitespace| {
+                let values = fields
+                    .map(|f| f.parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .filter(|v| v.len() == 3)
+                    .ok_or_else(|| invalid(format!("line {} isn't three numbers", num + 1)))?;
+                Ok::<_, HdtrError>([values[0], values[1], values[2]])
+            };
+
+            match keyword {
+                "LUT_3D_SIZE" => {
+                    let n = fields
+                        .next()
+                        .and_then(|n| n.parse::<usize>().ok())
+                        .filter(|&n| n >= 2)
+                        .ok_or_else(|| {
+                            invalid(format!("invalid LUT_3D_SIZE on line {}", num + 1))
+                        })?;
+                    size = Some(n);
+                }
+                // A 1D LUT's rows can't be told apart from the 3D table's.
+                "LUT_1D_SIZE" => return Err(invalid("1D LUTs aren't supported".into())),
+                "DOMAIN_MIN" => domain_min = triple(fields)?,
+                "DOMAIN_MAX" => domain_max = triple(fields)?,
+                "LUT_3D_INPUT_RANGE" => {
+                    let range = fields
+                        .map(|f| f.parse::<f64>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .ok()
+                        .filter(|r| r.len() == 2)
+                        .ok_or_else(|| {
+                            invalid(format!("invalid LUT_3D_INPUT_RANGE on line {}", num + 1))
+                        })?;
+                    domain_min = [range[0]; 3];
+                    domain_max = [range[1]; 3];
+                }
+                // Other keywords, such as vendors' own, don't affect the table.
+                _ if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {}
+                _ => table.push(triple(line.split_whitespace())?),
+            }
+        }
+
+        let size = size.ok_or_else(|| invalid("missing LUT_3D_SIZE".into()))?;
+        if table.len() != size * size * size {
+            return Err(invalid(format!(
+                "expected {} entries for a size of {size} but found {}",
+                size * size * size,
+                table.len()
+            )));
+        }
+
+        if (0..3).any(|c| domain_max[c] <= domain_min[c]) {
+            return Err(invalid("DOMAIN_MAX must exceed DOMAIN_MIN".into()));
+        }
+
+        Ok(Self {
+            size,
+            table,
+            domain_min,
+            domain_max,
+        })
+    }
+
+    /// Maps every pixel of `canvas` through the LUT.
+    pub fn apply(&self, canvas: &mut RgbImage) {
+        canvas.par_chunks_mut(3).for_each(|p| {
+            let out = self.lookup([p[0], p[1], p[2]]);
+            p.copy_from_slice(&out);
+        });
+    }
+
+    /// Looks up `rgb`, interpolating trilinearly between the eight surrounding entries.
+    fn lookup(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let last = (self.size - 1) as f64;
+
+        // Each channel's position along its axis, split into a lower index and a fraction.
+        let mut index = [0; 3];
+        let mut frac = [0.; 3];
+        for c in 0..3 {
+            let v = rgb[c] as f64 / 255.;
+            let t = (v - self.domain_min[c]) / (self.domain_max[c] - self.domain_min[c]);
+            let pos = t.clamp(0., 1.) * last;
+            index[c] = (pos.floor() as usize).min(self.size - 2);
+            frac[c] = pos - index[c] as f64;
+        }
+
+        let entry = |r: usize, g: usize, b: usize| {
+            self.table
+                [(index[2] + b) * self.size * self.size + (index[1] + g) * self.size + index[0] + r]
+        };
+
+        let mut out = [0.; 3];
+        for (b, wb) in [(0, 1. - frac[2]), (1, frac[2])] {
+            for (g, wg) in [(0, 1. - frac[1]), (1, frac[1])] {
+                for (r, wr) in [(0, 1. - frac[0]), (1, frac[0])] {
+                    let e = entry(r, g, b);
+                    for c in 0..3 {
+                        out[c] += e[c] * wr * wg * wb;
+                    }
+                }
+            }
+        }
+
+        out.map(|c| (c.clamp(0., 1.) * 255.).round() as u8)
+    }
+}
+
+/// Writes `contents` to a temporary `.cube` file named after `name` and parses it.
+#[cfg(test)]
+fn parse_test_cube(name: &str, contents: &str) -> Result<Lut, HdtrError> {
+    let path = std::env::temp_dir().join(format!("hdtr_test_{name}.cube"));
+    std::fs::write(&path, contents).unwrap();
+    Lut::from_cube(path)
+}
+
+/// The rows of an identity LUT with `size` entries along each axis.
+#[cfg(test)]
+fn identity_rows(size: usize) -> String {
+    let last = (size - 1) as f64;
+    let mut rows = String::new();
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                rows += &format!(
+                    "{} {} {}\n",
+                    r as f64 / last,
+                    g as f64 / last,
+                    b as f64 / last
+                );
+            }
+        }
+    }
+    rows
+}
+
+#[test]
+fn test_parse_cube() {
+    let cube = format!(
+        "# comment\nTITLE \"identity\"\nLUT_3D_SIZE 3\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 1 1 1\nLUT_3D_INPUT_RANGE 0 1\nVENDOR_KEYWORD 1\n{}",
+        identity_rows(3)
+    );
+    let lut = parse_test_cube("parse", &cube).unwrap();
+    assert_eq!(lut.size, 3);
+    assert_eq!(lut.table.len(), 27);
+    assert_eq!(lut.table[1], [0.5, 0., 0.]);
+
+    let cube = format!(
+        "LUT_3D_SIZE 2\nDOMAIN_MIN 0 0 0\nDOMAIN_MAX 0.5 1 1\n{}",
+        identity_rows(2)
+    );
+    assert_eq!(
+        parse_test_cube("domain", &cube).unwrap().domain_max,
+        [0.5, 1., 1.]
+    );
+}
+
+#[test]
+fn test_cube_size_mismatch() {
+    let cube = format!("LUT_3D_SIZE 3\n{}", identity_rows(2));
+    assert!(matches!(
+        parse_test_cube("mismatch", &cube),
+        Err(HdtrError::InvalidLut { .. })
+    ));
+    assert!(parse_test_cube("missing_size", &identity_rows(2)).is_err());
+}
+
+#[test]
+fn test_identity_lookup() {
+    let lut = parse_test_cube("identity", &format!("LUT_3D_SIZE 2\n{}", identity_rows(2))).unwrap();
+
+    // Trilinear interpolation between the corners of an identity LUT reproduces every color.
+    for rgb in [[0, 0, 0], [255, 255, 255], [10, 128, 240], [77, 3, 199]] {
+        assert_eq!(lut.lookup(rgb), rgb);
+    }
+
+    let mut canvas = RgbImage::from_pixel(2, 2, image::Rgb([12, 34, 56]));
+    lut.apply(&mut canvas);
+    assert!(canvas.pixels().all(|p| p.0 == [12, 34, 56]));
+}
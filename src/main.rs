@@ -1,31 +1,148 @@
-use std::path::Path;
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use colored::*;
-use hdtr::{pipeline::Pipeline, HdtrError, InputImage};
+use hdtr::{
+    pipeline::{Pipeline, Progress},
+    HdtrError, InputImage,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use notify::{RecursiveMode, Watcher};
+
+/// The longest edge, in pixels, of the inputs when running with `--preview`.
+const PREVIEW_MAX_DIM: u32 = 1024;
+
+/// How long `--watch` waits for changes to settle before re-running, so that an editor saving
+/// several files, or one file in several writes, triggers a single run.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
 
 fn main() -> Result<(), HdtrError> {
     let mut example_images = None;
     let mut pipeline = None;
+    let mut pipeline_path = None;
+    let mut watch = false;
     let mut check_pipeline = false;
+    let mut preview = false;
+    let mut masks_only = false;
+    let mut strict = false;
+    let mut verbose = false;
+    let mut no_progress = false;
+    let mut timing_json = false;
+    let mut threads = None;
+    let mut example_extension = "json";
+    let mut debug_map = None;
+    let mut report = None;
+    let mut resolved = None;
 
-    for arg in std::env::args().skip(1) {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
         if arg == "--help" || arg == "-h" {
             usage();
             return Ok(());
-        } else if arg.ends_with(".json") {
-            let json = std::fs::read_to_string(&arg)?;
-            pipeline = Some(serde_json::from_str::<Pipeline>(&json)?);
+        } else if [".json", ".toml", ".yaml", ".yml"]
+            .iter()
+            .any(|ext| arg.ends_with(ext))
+        {
+            pipeline = Some(load_pipeline(&arg)?);
+            pipeline_path = Some(arg);
         } else if arg == "--example" {
             example_images = Some(Vec::new());
+        } else if arg == "--debug-map" {
+            match args.next() {
+                Some(path) => debug_map = Some(path),
+                None => {
+                    eprintln!("--debug-map must be followed by an output path");
+                    usage();
+                    return Ok(());
+                }
+            }
+        } else if arg == "--report" {
+            match args.next() {
+                Some(path) => report = Some(path),
+                None => {
+                    eprintln!("--report must be followed by an output path");
+                    usage();
+                    return Ok(());
+                }
+            }
+        } else if arg == "--resolved" {
+            match args.next() {
+                Some(path) => resolved = Some(path),
+                None => {
+                    eprintln!("--resolved must be followed by an output path");
+                    usage();
+                    return Ok(());
+                }
+            }
+        } else if arg == "--toml" {
+            example_extension = "toml";
+        } else if arg == "--yaml" {
+            example_extension = "yaml";
         } else if arg == "--check" {
             check_pipeline = true;
-        } else if example_images.is_some() && InputImage::new(&arg).is_ok() {
-            example_images.as_mut().unwrap().push(arg);
+        } else if arg == "--preview" {
+            preview = true;
+        } else if arg == "--masks-only" {
+            masks_only = true;
+        } else if arg == "--strict" {
+            strict = true;
+        } else if arg == "--verbose" || arg == "-v" {
+            verbose = true;
+        } else if arg == "--watch" {
+            watch = true;
+        } else if arg == "--no-progress" {
+            no_progress = true;
+        } else if arg == "--timing-json" {
+            timing_json = true;
+        } else if arg == "--threads" {
+            match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                Some(n) if n > 0 => threads = Some(n),
+                _ => {
+                    eprintln!("--threads must be followed by a positive integer");
+                    usage();
+                    std::process::exit(1);
+                }
+            }
+        } else if let Some(images) = example_images
+            .as_mut()
+            .filter(|_| InputImage::new(&arg).is_ok())
+        {
+            images.push(arg);
         } else {
             eprintln!("Unexpected argument: {arg}");
         }
     }
 
+    if let Some(n) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build_global()
+            .map_err(|e| e.to_string())?;
+    }
+
+    // Command-line overrides, applied again whenever `--watch` reloads the pipeline.
+    let configure = |p: &mut Pipeline| {
+        if let Some(path) = &debug_map {
+            p.debug_map = Some(path.clone());
+        }
+
+        if strict {
+            p.strict = Some(true);
+        }
+
+        if verbose {
+            p.verbose = Some(true);
+        }
+    };
+
+    if let Some(p) = pipeline.as_mut() {
+        configure(p);
+    }
+
     match (example_images, pipeline) {
         (None, None) => {
             usage();
@@ -36,9 +153,49 @@ fn main() -> Result<(), HdtrError> {
             println!("No problems found in pipeline. This does not guarantee success -- image files must be valid and the same dimensions, for example.");
             Ok(())
         }
-        (None, Some(p)) => p.execute(),
-        (Some(i), None) if i.is_empty() => save_example(None),
-        (Some(i), None) => save_example(Some(i)),
+        (None, Some(mut p)) => {
+            // Returns the files written besides the pipeline's outputs, so watching can ignore them.
+            let run = |p: &mut Pipeline| -> Result<Vec<PathBuf>, HdtrError> {
+                let outcome = if masks_only {
+                    p.execute_masks_only()?
+                } else if preview {
+                    p.execute_preview(PREVIEW_MAX_DIM)?
+                } else if !no_progress && std::io::stdout().is_terminal() {
+                    // Progress bars would garble output that's redirected to a file.
+                    let bar = progress_bar(p.filenames.len());
+                    let result = p.execute_with_progress(|progress| show_progress(&bar, progress));
+                    bar.finish_and_clear();
+                    result?
+                } else {
+                    p.execute()?
+                };
+
+                if timing_json {
+                    println!("{}", serde_json::to_string(&outcome.timings)?);
+                }
+
+                let mut written = outcome.saved_masks;
+                if let (Some(path), Some(report)) = (&report, outcome.report) {
+                    std::fs::write(path, serde_json::to_string_pretty(&report)?)?;
+                    println!("Saved report @ '{}'", path.green());
+                    written.push(path.into());
+                }
+
+                if let Some(path) = &resolved {
+                    std::fs::write(path, serde_json::to_string_pretty(&p.resolved())?)?;
+                    println!("Saved resolved pipeline @ '{}'", path.green());
+                    written.push(path.into());
+                }
+                Ok(written)
+            };
+
+            match pipeline_path {
+                Some(path) if watch => watch_pipeline(&path, p, run, configure),
+                _ => run(&mut p).map(|_| ()),
+            }
+        }
+        (Some(i), None) if i.is_empty() => save_example(None, example_extension),
+        (Some(i), None) => save_example(Some(i), example_extension),
         (Some(_), Some(_)) => {
             eprintln!("--example may not be specified with a pipeline file");
             usage();
@@ -47,6 +204,152 @@ fn main() -> Result<(), HdtrError> {
     }
 }
 
+/// Reads a JSON, TOML or YAML pipeline file, according to its extension.
+fn load_pipeline(path: &str) -> Result<Pipeline, HdtrError> {
+    let contents = std::fs::read_to_string(path)?;
+    if path.ends_with(".toml") {
+        Ok(toml::from_str::<Pipeline>(&contents)?)
+    } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+        Pipeline::from_yaml(&contents)
+    } else {
+        Ok(serde_json::from_str::<Pipeline>(&contents)?)
+    }
+}
+
+/// Runs `p` with `run`, then again each time the pipeline file at `path` or one of its inputs
+/// changes, until interrupted. The file is reloaded and passed to `configure` before each re-run.
+/// Failed runs are reported without ending the watch. The pipeline's outputs and the files `run`
+/// returns are ignored, since writing them would otherwise trigger the next run.
+fn watch_pipeline(
+    path: &str,
+    mut p: Pipeline,
+    run: impl Fn(&mut Pipeline) -> Result<Vec<PathBuf>, HdtrError>,
+    configure: impl Fn(&mut Pipeline),
+) -> Result<(), HdtrError> {
+    let mut written = Vec::new();
+    loop {
+        match run(&mut p) {
+            Ok(files) => written = files,
+            Err(e) => eprintln!("{} {e:?}", "Run failed:".red()),
+        }
+
+        loop {
+            let absolute = |f: PathBuf| std::path::absolute(&f).unwrap_or(f);
+            let watched = std::iter::once(PathBuf::from(path))
+                .chain(p.input_paths())
+                .map(absolute)
+                .collect::<Vec<_>>();
+            let ignored = p
+                .output_paths()
+                .into_iter()
+                .chain(written.iter().cloned())
+                .map(absolute)
+                .collect::<Vec<_>>();
+            println!("Watching {} files for changes...", watched.len());
+            let changed = wait_for_change(&watched, &ignored).map_err(|e| e.to_string())?;
+
+            println!(
+                "[{}] {} changed; re-running",
+                timestamp(),
+                changed.display().to_string().cyan()
+            );
+            match load_pipeline(path) {
+                Ok(reloaded) => {
+                    p = reloaded;
+                    configure(&mut p);
+                    break;
+                }
+                Err(e) => eprintln!("{} {e:?}", format!("Couldn't reload {path}:").red()),
+            }
+        }
+    }
+}
+
+/// Blocks until one of `paths`, or a file in one of them that's a directory, is changed, then
+/// waits for changes to settle and returns the first one changed. Parent directories are watched
+/// rather than the files themselves so that editors which save by replacing a file are noticed.
+/// Changes to `ignored` files don't count.
+fn wait_for_change(paths: &[PathBuf], ignored: &[PathBuf]) -> notify::Result<PathBuf> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in paths {
+        let dir = match path.is_dir() {
+            true => path.as_path(),
+            false => path.parent().unwrap_or(Path::new(".")),
+        };
+        // Inputs that don't exist yet can't be watched, but their run will already have failed.
+        let _ = watcher.watch(dir, RecursiveMode::NonRecursive);
+    }
+
+    let changed = loop {
+        let event = rx
+            .recv()
+            .map_err(|e| notify::Error::generic(&e.to_string()))??;
+        let modifies = event.kind.is_create() || event.kind.is_modify() || event.kind.is_remove();
+        if let Some(changed) = event
+            .paths
+            .into_iter()
+            .find(|p| modifies && is_relevant(p, paths, ignored))
+        {
+            break changed;
+        }
+    };
+
+    while rx.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+    Ok(changed)
+}
+
+/// Whether a change to `changed` should re-run a pipeline watching `paths`: it's one of them, or
+/// in one that's a directory, and isn't one of the `ignored` outputs.
+fn is_relevant(changed: &Path, paths: &[PathBuf], ignored: &[PathBuf]) -> bool {
+    !ignored.iter().any(|i| i == changed)
+        && paths
+            .iter()
+            .any(|p| changed == p || (p.is_dir() && changed.parent() == Some(p)))
+}
+
+/// The current UTC time of day as `HH:MM:SS`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// A bar showing how many of `total` input files have loaded.
+fn progress_bar(total: usize) -> ProgressBar {
+    ProgressBar::new(total as u64).with_style(
+        ProgressStyle::with_template("Loading {bar:40} {pos}/{len} images")
+            .expect("Progress template is valid"),
+    )
+}
+
+/// Shows a run's `progress` on `bar` while its images load and as a spinner afterwards, printing
+/// its status lines above it.
+fn show_progress(bar: &ProgressBar, progress: Progress) {
+    match progress {
+        Progress::Loaded { done, total } => {
+            bar.set_length(total as u64);
+            bar.set_position(done as u64);
+        }
+        Progress::Stage(stage) => {
+            bar.set_style(
+                ProgressStyle::with_template("{spinner} {msg} ({elapsed})")
+                    .expect("Spinner template is valid"),
+            );
+            bar.set_message(stage);
+            bar.enable_steady_tick(Duration::from_millis(100));
+        }
+        Progress::Message(message) => bar.println(message),
+    }
+}
+
 fn usage() {
     let exe = std::env::args().next();
     let exe = exe
@@ -75,6 +378,79 @@ fn usage() {
         "pipeline".yellow()
     );
 
+    println!(
+        "    {} --preview {}     -- Quickly generates a low-resolution {} image",
+        exe.green(),
+        "pipeline.json".yellow(),
+        "preview_".yellow()
+    );
+
+    println!(
+        "    {} --masks-only {}  -- Generates and saves masks without blending",
+        exe.green(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --strict {}      -- Fails instead of warning about problems such as uncovered pixels",
+        exe.green(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --verbose {}     -- Also prints each image's dimensions and assigned band",
+        exe.green(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --watch {}       -- Runs again whenever the {} file or its inputs change",
+        exe.green(),
+        "pipeline.json".yellow(),
+        "pipeline".yellow()
+    );
+
+    println!(
+        "    {} --no-progress {} -- Prints status lines instead of a progress bar",
+        exe.green(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --timing-json {} -- Also prints how long each step took as JSON",
+        exe.green(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --debug-map {} {} -- Also saves a false-color map of which image dominates where",
+        exe.green(),
+        "map.png".magenta(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --report {} {} -- Also saves a JSON summary of the inputs and output",
+        exe.green(),
+        "report.json".magenta(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --resolved {} {} -- Also saves the settings used, with defaults filled in, as JSON",
+        exe.green(),
+        "resolved.json".magenta(),
+        "pipeline.json".yellow()
+    );
+
+    println!(
+        "    {} --threads {} {}   -- Limits processing to {} worker threads",
+        exe.green(),
+        "N".magenta(),
+        "pipeline.json".yellow(),
+        "N".magenta()
+    );
+
     println!(
         "    {} --example                   -- Creates a sample pipeline pipeline file",
         exe.green(),
@@ -89,16 +465,32 @@ fn usage() {
         "input files".cyan(),
     );
 
+    println!(
+        "    {} --example --toml            -- Creates a sample pipeline file in TOML format",
+        exe.green(),
+    );
+
+    println!(
+        "    {} --example --yaml            -- Creates a sample pipeline file in YAML format",
+        exe.green(),
+    );
+
     println!();
 
+    println!(
+        "Pipeline files may be written as JSON ({}), TOML ({}) or YAML ({}).",
+        ".json".yellow(),
+        ".toml".yellow(),
+        ".yaml".yellow()
+    );
     println!("To perform HDTR processing, images are expected to have exactly equal dimensions.");
 }
 
-fn save_example(images: Option<Vec<String>>) -> Result<(), HdtrError> {
+fn save_example(images: Option<Vec<String>>, extension: &str) -> Result<(), HdtrError> {
     const EXAMPLE_FILE_STEM: &str = "example_pipeline";
 
     for num in 1u32.. {
-        let filename = format!("{EXAMPLE_FILE_STEM}{num}.json");
+        let filename = format!("{EXAMPLE_FILE_STEM}{num}.{extension}");
         if !Path::new(&filename).exists() {
             Pipeline::save_example(&filename, images)?;
 
@@ -109,3 +501,22 @@ fn save_example(images: Option<Vec<String>>) -> Result<(), HdtrError> {
 
     unreachable!()
 }
+
+#[test]
+fn test_watch_ignores_outputs() {
+    let dir = std::env::temp_dir().join("hdtr_test_watch");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths = [dir.clone(), dir.join("pipeline.json")];
+    let ignored = [dir.join("out.png"), dir.join("img_mask.png")];
+
+    assert!(is_relevant(&dir.join("img.png"), &paths, &ignored));
+    assert!(is_relevant(&dir.join("pipeline.json"), &paths, &ignored));
+    for output in &ignored {
+        assert!(!is_relevant(output, &paths, &ignored));
+    }
+    assert!(!is_relevant(
+        &std::env::temp_dir().join("other.png"),
+        &paths,
+        &ignored
+    ));
+}
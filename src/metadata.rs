@@ -0,0 +1,253 @@
+//! Carries camera EXIF metadata from an input image over to the blended output, and embeds color
+//! profiles in it, since `image`'s encoders support neither.
+
+use std::{
+    io::{Cursor, Write},
+    path::Path,
+};
+
+use exif::{experimental::Writer, In, Reader, Tag};
+use image::ImageFormat;
+
+use crate::HdtrError;
+
+/// The EXIF fields copied to the output. Fields describing the pixel data itself, such as
+/// dimensions or thumbnails, aren't copied since the blend doesn't preserve them.
+const COPIED_TAGS: [Tag; 13] = [
+    Tag::Orientation,
+    Tag::Make,
+    Tag::Model,
+    Tag::LensModel,
+    Tag::DateTime,
+    Tag::DateTimeOriginal,
+    Tag::DateTimeDigitized,
+    Tag::ExposureTime,
+    Tag::FNumber,
+    Tag::PhotographicSensitivity,
+    Tag::FocalLength,
+    Tag::Artist,
+    Tag::Copyright,
+];
+
+/// Whether `format` can carry EXIF metadata written by `write_exif`.
+pub(crate) fn supports_exif(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png)
+}
+
+/// Reads the copied fields of `path`'s EXIF metadata, encoded as a TIFF structure. Returns `None`
+/// if the image can't be read or has none of the copied fields.
+pub(crate) fn read_exif(path: &Path) -> Result<Option<Vec<u8>>, HdtrError> {
+    // Frames of an animation have no file of their own.
+    let Ok(file) = std::fs::File::open(path) else {
+        return Ok(None);
+    };
+    let Ok(exif) = Reader::new().read_from_container(&mut std::io::BufReader::new(file)) else {
+        return Ok(None);
+    };
+
+    let fields = COPIED_TAGS
+        .iter()
+        .filter_map(|&tag| exif.get_field(tag, In::PRIMARY))
+        .collect::<Vec<_>>();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+
+    let mut tiff = Cursor::new(Vec::new());
+    writer
+        .write(&mut tiff, exif.little_endian())
+        .map_err(|e| e.to_string())?;
+    Ok(Some(tiff.into_inner()))
+}
+
+/// Reads the DateTimeOriginal field of `path`'s EXIF metadata, formatted so that later times
+/// sort after earlier ones. Returns `None` if the image can't be read or has no such field.
+pub(crate) fn capture_time(path: &Path) -> Option<String> {
+    let file = std::fs::File::open(path).ok()?;
+    let exif = Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+    let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?;
+    Some(field.display_value().to_string())
+}
+
+/// Embeds `tiff`, as returned by `read_exif`, into the JPEG or PNG file at `destination`.
+pub(crate) fn write_exif(
+    destination: &Path,
+    format: ImageFormat,
+    tiff: &[u8],
+) -> Result<(), HdtrError> {
+    let mut bytes = std::fs::read(destination)?;
+
+    match format {
+        ImageFormat::Jpeg => {
+            // An APP1 segment after the start-of-image marker and JFIF header
+            const HEADER: &[u8] = b"Exif\0\0";
+            let length = u16::try_from(2 + HEADER.len() + tiff.len())
+                .map_err(|_| "EXIF metadata is too large for a JPEG segment")?;
+
+            let mut segment = vec![0xFF, 0xE1];
+            segment.extend_from_slice(&length.to_be_bytes());
+            segment.extend_from_slice(HEADER);
+            segment.extend_from_slice(tiff);
+            let offset = jpeg_segments_end(&bytes);
+            bytes.splice(offset..offset, segment);
+        }
+        ImageFormat::Png => {
+            // An eXIf chunk directly after the signature and IHDR chunk
+            bytes.splice(IHDR_END..IHDR_END, png_chunk(b"eXIf", tiff));
+        }
+        _ => {
+            return Err(HdtrError::HDTR(
+                format!("EXIF can't be written to {format:?} files").into(),
+            ))
+        }
+    }
+
+    std::fs::write(destination, bytes)?;
+    Ok(())
+}
+
+/// The offset in `jpeg` after its start-of-image marker and any APP0 (JFIF) and APP1 (EXIF)
+/// segments directly following it. JFIF requires its APP0 segment to come first, so other
+/// segments are inserted here.
+fn jpeg_segments_end(jpeg: &[u8]) -> usize {
+    let mut offset = 2;
+    while let Some(&[0xFF, 0xE0 | 0xE1, high, low, ..]) = jpeg.get(offset..) {
+        let end = offset + 2 + u16::from_be_bytes([high, low]) as usize;
+        // A length running past the end of the file is corrupt, so nothing is skipped for it.
+        if end > jpeg.len() {
+            break;
+        }
+        offset = end;
+    }
+    offset.min(jpeg.len())
+}
+
+/// The end of a PNG's signature and IHDR chunk, after which ancillary chunks are inserted.
+const IHDR_END: usize = 8 + 25;
+
+/// Encodes a PNG chunk of type `kind` holding `data`, with its length and CRC.
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    let crc = crc32fast::hash(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+/// A color profile to embed in the output.
+pub(crate) enum ColorProfile {
+    /// Marks the output as sRGB without a full profile.
+    Srgb,
+    /// The contents of an ICC profile file.
+    Icc(Vec<u8>),
+}
+
+impl ColorProfile {
+    /// Reads the profile named by a pipeline's `color_profile`: `"sRGB"`, or the path of an ICC
+    /// profile.
+    pub(crate) fn load(name: &str) -> Result<Self, HdtrError> {
+        if name.eq_ignore_ascii_case("srgb") {
+            Ok(Self::Srgb)
+        } else if Path::new(name).exists() {
+            Ok(Self::Icc(std::fs::read(name)?))
+        } else {
+            Err(HdtrError::InputFileDoesNotExist(name.to_string()))
+        }
+    }
+
+    /// Whether the profile can be embedded in `format` by `embed`. PNGs can carry either kind,
+    /// while JPEGs can only carry ICC profiles since they have no sRGB marker.
+    pub(crate) fn supported_by(&self, format: ImageFormat) -> bool {
+        matches!(
+            (self, format),
+            (_, ImageFormat::Png) | (Self::Icc(_), ImageFormat::Jpeg)
+        )
+    }
+
+    /// Embeds the profile into the PNG or JPEG file at `destination`, as an `sRGB` or `iCCP`
+    /// chunk, or as APP2 segments, respectively.
+    pub(crate) fn embed(&self, destination: &Path, format: ImageFormat) -> Result<(), HdtrError> {
+        let mut bytes = std::fs::read(destination)?;
+
+        match (self, format) {
+            (Self::Srgb, ImageFormat::Png) => {
+                // Perceptual rendering intent
+                bytes.splice(IHDR_END..IHDR_END, png_chunk(b"sRGB", &[0]));
+            }
+            (Self::Icc(icc), ImageFormat::Png) => {
+                // The profile's name, a compression method of zero, then the deflated profile
+                let mut data = b"ICC profile\0\0".to_vec();
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(&mut data, flate2::Compression::default());
+                encoder.write_all(icc)?;
+                encoder.finish()?;
+                bytes.splice(IHDR_END..IHDR_END, png_chunk(b"iCCP", &data));
+            }
+            (Self::Icc(icc), ImageFormat::Jpeg) => {
+                // Profiles too large for one segment are split across several, each numbered
+                // and giving the total count.
+                const HEADER: &[u8] = b"ICC_PROFILE\0";
+                const MAX_DATA: usize = u16::MAX as usize - 2 - HEADER.len() - 2;
+                let chunks = icc.chunks(MAX_DATA).collect::<Vec<_>>();
+                let count = u8::try_from(chunks.len())
+                    .map_err(|_| "ICC profile is too large for a JPEG")?;
+
+                let mut segments = Vec::new();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let length = (2 + HEADER.len() + 2 + chunk.len()) as u16;
+                    segments.extend_from_slice(&[0xFF, 0xE2]);
+                    segments.extend_from_slice(&length.to_be_bytes());
+                    segments.extend_from_slice(HEADER);
+                    segments.extend_from_slice(&[i as u8 + 1, count]);
+                    segments.extend_from_slice(chunk);
+                }
+                let offset = jpeg_segments_end(&bytes);
+                bytes.splice(offset..offset, segments);
+            }
+            _ => {
+                return Err(HdtrError::HDTR(
+                    format!("This color profile can't be written to {format:?} files").into(),
+                ))
+            }
+        }
+
+        std::fs::write(destination, bytes)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_jpeg_icc_round_trip() {
+    let path = std::env::temp_dir().join("hdtr_test_icc.jpg");
+    image::RgbImage::from_pixel(8, 8, image::Rgb([40, 80, 120]))
+        .save(&path)
+        .unwrap();
+    let icc = (0..70_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    ColorProfile::Icc(icc.clone())
+        .embed(&path, ImageFormat::Jpeg)
+        .unwrap();
+
+    // The JFIF header stays first, and the profile spans two segments that decode back whole.
+    let bytes = std::fs::read(&path).unwrap();
+    assert_eq!(bytes[..4], [0xFF, 0xD8, 0xFF, 0xE0]);
+    let mut decoder =
+        image::codecs::jpeg::JpegDecoder::new(std::fs::File::open(&path).unwrap()).unwrap();
+    assert_eq!(image::ImageDecoder::icc_profile(&mut decoder), Some(icc));
+    assert_eq!(image::open(&path).unwrap().to_rgb8().dimensions(), (8, 8));
+}
+
+#[test]
+fn test_jpeg_segments_end_truncated() {
+    // An APP0 segment claiming 16 bytes in a file that ends after 4 of them.
+    let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F'];
+    assert_eq!(jpeg_segments_end(&jpeg), 2);
+    assert_eq!(jpeg_segments_end(&[0xFF]), 1);
+}
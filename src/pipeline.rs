@@ -1,35 +1,66 @@
-use crate::{HdtrError, InputImage, InputImages};
-use image::{DynamicImage, GenericImageView, Pixel, RgbImage};
+use crate::{
+    apply_gamma, channel_mask, format_from_path, is_url, k_for_seam_width, metadata,
+    metadata::ColorProfile, open_image, read_tiff_pages, write_jpeg, write_png, write_webp,
+    HdtrError, InputImage, InputImages, Lut, Rgb16Image, ALIGN_SEARCH_RADIUS, DEFAULT_MASK_SUFFIX,
+};
+use image::{
+    codecs::png::{CompressionType, FilterType as PngFilter},
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, ImageBuffer, ImageFormat, PixelWithColorType, RgbImage,
+};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{
+    borrow::Cow,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
 
 pub enum Mask {
     Default,
     Path(String),
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PipelineInputImage {
     image: String,
     mask: Option<String>,
 }
 
 impl PipelineInputImage {
-    pub(crate) fn load(&self) -> Result<(InputImage, DynamicImage), HdtrError> {
-        let image = InputImage::new(&self.image)?;
+    /// Loads the image and its mask file, if it has one. If `run`'s pipeline sets `scale_masks_to_images`, a mask
+    /// whose dimensions differ from the image's is resized to match with bilinear interpolation
+    /// rather than rejected.
+    fn load(&self, run: &Run) -> Result<(InputImage, Option<DynamicImage>), HdtrError> {
+        let image = if is_url(&self.image) {
+            InputImage::from_url(&self.image)?
+        } else {
+            InputImage::new(&self.image)?
+        };
 
         let expected = image.im.dimensions();
         let mask_filename = match &self.mask {
             Some(f) => f,
-            None => return Ok((image, default_mask(expected))),
+            None => return Ok((image, None)),
         };
 
-        let mask = image::open(mask_filename)?;
+        let mask = open_image(mask_filename)?;
 
         // check the dimensions
         let received = mask.dimensions();
-        if expected != received {
+        if expected != received && run.scale_masks_to_images == Some(true) {
+            run.warn(format!(
+                "Resized {mask_filename} from {}x{} to {}x{} to match {}",
+                received.0, received.1, expected.0, expected.1, self.image
+            ));
+            let mask = mask.resize_exact(expected.0, expected.1, FilterType::Triangle);
+            Ok((image, Some(mask)))
+        } else if expected != received {
             Err(HdtrError::DimensionMismatch {
                 expected,
                 received,
@@ -40,9 +71,135 @@ impl PipelineInputImage {
                 .into(),
             })
         } else {
-            Ok((image, mask))
+            Ok((image, Some(mask)))
+        }
+    }
+
+    /// Whether this entry refers to several inputs: the frames of an animated GIF or the images
+    /// in a directory. Such entries can't have a mask.
+    pub(crate) fn expands(&self) -> bool {
+        !is_url(&self.image) && (Path::new(&self.image).is_dir() || InputImage::is_gif(&self.image))
+    }
+
+    /// Loads this entry's images without their masks, expanding it if necessary.
+    pub(crate) fn load_images(&self) -> Result<Vec<InputImage>, HdtrError> {
+        let path = Path::new(&self.image);
+        if is_url(&self.image) {
+            Ok(vec![InputImage::from_url(&self.image)?])
+        } else if path.is_dir() {
+            directory_images(path)?
+                .iter()
+                .map(InputImage::new)
+                .collect()
+        } else if InputImage::is_gif(path) {
+            InputImage::from_gif(path)
+        } else {
+            Ok(vec![InputImage::new(path)?])
+        }
+    }
+
+    /// Loads this entry with its mask file, or without masks if it expands into several inputs.
+    /// `run` is passed on to `load`.
+    fn load_all(&self, run: &Run) -> Result<Vec<(InputImage, Option<DynamicImage>)>, HdtrError> {
+        if self.expands() {
+            Ok(self
+                .load_images()?
+                .into_iter()
+                .map(|image| (image, None))
+                .collect())
+        } else {
+            Ok(vec![self.load(run)?])
+        }
+    }
+}
+
+/// How far, as a fraction, `match_exposure` may scale an image before warning about it.
+const EXPOSURE_DRIFT_THRESHOLD: f64 = 0.25;
+
+/// The JPEG quality used when `quality` isn't set, matching `image`'s own default.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// The formats a pipeline's blended image can be saved as.
+const SUPPORTED_OUTPUT_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Png,
+    ImageFormat::Jpeg,
+    ImageFormat::Bmp,
+    ImageFormat::Tiff,
+    ImageFormat::WebP,
+    ImageFormat::Tga,
+];
+
+/// Prints a status line, or passes it to the run's progress callback if it has one.
+macro_rules! status {
+    ($pipeline:expr, $($arg:tt)*) => {
+        $pipeline.status(format!($($arg)*))
+    };
+}
+
+/// Variations on how `Pipeline::run` executes the pipeline.
+#[derive(Default, Clone, Copy)]
+struct RunOptions {
+    /// Downscale inputs so their longer edge is at most this many pixels.
+    preview: Option<u32>,
+    /// Stop after saving the masks.
+    masks_only: bool,
+}
+
+/// A blended output, in the bit depth it was composited at.
+enum Blended {
+    Rgb8(RgbImage),
+    Rgb16(Rgb16Image),
+}
+
+/// Checks that `index`, described by `name`, refers to one of `count` images.
+fn check_index(name: &str, index: usize, count: usize) -> Result<(), HdtrError> {
+    if index < count {
+        Ok(())
+    } else {
+        Err(HdtrError::PipelineError(
+            format!("{name} is {index} but there are only {count} images").into(),
+        ))
+    }
+}
+
+/// Checks that `region`, given as `[x, y, width, height]`, lies within images of `dimensions`.
+fn check_region(region: [u32; 4], (width, height): (u32, u32)) -> Result<[u32; 4], HdtrError> {
+    let [x, y, w, h] = region;
+    if x as u64 + w as u64 > width as u64 || y as u64 + h as u64 > height as u64 {
+        return Err(HdtrError::PipelineError(
+            format!("region {region:?} extends beyond the {width}x{height} images").into(),
+        ));
+    }
+
+    Ok(region)
+}
+
+/// Lists the images directly within `dir`, sorted by path.
+fn directory_images(dir: &Path) -> Result<Vec<PathBuf>, HdtrError> {
+    const EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "bmp", "tif", "tiff"];
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_image = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+            .unwrap_or(false);
+
+        if is_image && path.is_file() {
+            paths.push(path);
         }
     }
+
+    if paths.is_empty() {
+        return Err(HdtrError::PipelineError(
+            format!("{} contains no images", dir.display()).into(),
+        ));
+    }
+
+    paths.sort();
+    Ok(paths)
 }
 
 impl<S: Into<String>> From<S> for PipelineInputImage {
@@ -54,28 +211,560 @@ impl<S: Into<String>> From<S> for PipelineInputImage {
     }
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub enum MaskType {
     VerticalFlat,
     HorizontalFlat,
-    VerticalLogistic { k: f64 },
-    HorizontalLogistic { k: f64 },
+    /// Like `VerticalFlat`, but each band extends `overlap * band_width` past its edges, ramping
+    /// linearly into its neighbors so they crossfade. `overlap` must be in `[0, 1)`.
+    VerticalFlatFeather {
+        overlap: f64,
+    },
+    VerticalLogistic {
+        k: f64,
+    },
+    /// Like `VerticalLogistic`, but each pixel's weight is jittered by up to `amplitude` levels
+    /// (out of 255) to break up contour banding in smooth gradients. The jitter is a hash of the
+    /// pixel's position, so output is reproducible.
+    VerticalLogisticDithered {
+        k: f64,
+        amplitude: f64,
+    },
+    HorizontalLogistic {
+        k: f64,
+    },
+    /// Like `VerticalLogistic`, but horizontal distances wrap around the image's edges, so the
+    /// last band blends into the first and the output tiles horizontally. `VerticalLogistic`
+    /// instead fades out toward the edges.
+    VerticalLogisticWrap {
+        k: f64,
+    },
+    /// Like `VerticalLogistic`, but each seam is shifted horizontally by
+    /// `amplitude * sin(2 * pi * y / wavelength)` pixels, producing a wave.
+    VerticalSine {
+        k: f64,
+        amplitude: f64,
+        wavelength: f64,
+    },
+    /// Logistic bands along the diagonal, sweeping from the first image in the top-left corner
+    /// to the last image in the bottom-right.
+    CornerWipe {
+        k: f64,
+    },
+    /// Logistic pie-slice wedges around the image's center, assigned clockwise starting from 12
+    /// o'clock.
+    Angular {
+        k: f64,
+    },
+    /// Like `Angular`, but the wedges twist
+    /// by `turns` full revolutions between the center and the corners. With `turns` of zero the
+    /// wedges are straight pie slices.
+    Spiral {
+        turns: f64,
+        k: f64,
+    },
+    /// Bands of a plus shape, running outward from the image's center: the first image gets the
+    /// central column and row, and each later one a pair of columns and a pair of rows further
+    /// out. A pixel's weight is the larger of its vertical and horizontal logistic weights.
+    Cross {
+        k: f64,
+    },
+    /// Hard concentric rings around the image's center, like a bullseye: image `i` gets the
+    /// pixels whose distance from the center is in `[i / n, (i + 1) / n)` of the distance to the
+    /// corners, so the first image is the central disc and the last reaches the corners.
+    RadialFlat,
+    /// Divides the image into `cols` by `rows` tiles, assigning the images to tiles in turn, left
+    /// to right and then top to bottom.
+    Grid {
+        cols: u32,
+        rows: u32,
+    },
+}
+
+impl MaskType {
+    /// Whether this mask type has a steepness `k` that `ks` and `seam_width` can replace.
+    pub fn has_k(&self) -> bool {
+        !matches!(
+            self,
+            MaskType::VerticalFlat
+                | MaskType::HorizontalFlat
+                | MaskType::VerticalFlatFeather { .. }
+                | MaskType::RadialFlat
+                | MaskType::Grid { .. }
+        )
+    }
+
+    /// Returns this mask type with its steepness replaced by `k`. Types without a steepness are
+    /// returned unchanged.
+    pub fn with_k(self, k: f64) -> Self {
+        match self {
+            MaskType::VerticalLogistic { .. } => MaskType::VerticalLogistic { k },
+            MaskType::VerticalLogisticDithered { amplitude, .. } => {
+                MaskType::VerticalLogisticDithered { k, amplitude }
+            }
+            MaskType::HorizontalLogistic { .. } => MaskType::HorizontalLogistic { k },
+            MaskType::VerticalLogisticWrap { .. } => MaskType::VerticalLogisticWrap { k },
+            MaskType::VerticalSine {
+                amplitude,
+                wavelength,
+                ..
+            } => MaskType::VerticalSine {
+                k,
+                amplitude,
+                wavelength,
+            },
+            MaskType::CornerWipe { .. } => MaskType::CornerWipe { k },
+            MaskType::Angular { .. } => MaskType::Angular { k },
+            MaskType::Cross { .. } => MaskType::Cross { k },
+            MaskType::Spiral { turns, .. } => MaskType::Spiral { turns, k },
+            MaskType::VerticalFlat
+            | MaskType::HorizontalFlat
+            | MaskType::VerticalFlatFeather { .. }
+            | MaskType::RadialFlat
+            | MaskType::Grid { .. } => self,
+        }
+    }
+}
+
+/// The mask types `generate_masks` gives the images: either one type shared by every image, such
+/// as `{"VerticalLogistic": {"k": 0.01}}`, or a list with one type per image, to mix geometries
+/// in a single composite.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum MaskSpec {
+    Uniform(MaskType),
+    PerImage(Vec<MaskType>),
+}
+
+impl MaskSpec {
+    /// The distinct mask types, which for `Uniform` is just the one.
+    pub fn mask_types(&self) -> &[MaskType] {
+        match self {
+            MaskSpec::Uniform(mask_type) => std::slice::from_ref(mask_type),
+            MaskSpec::PerImage(mask_types) => mask_types,
+        }
+    }
+}
+
+impl From<MaskType> for MaskSpec {
+    fn from(mask_type: MaskType) -> Self {
+        MaskSpec::Uniform(mask_type)
+    }
+}
+
+/// How the input images are combined into the output.
+#[derive(Serialize, Deserialize, Copy, Clone, Default)]
+pub enum BlendMode {
+    /// A weighted sum of the inputs according to their masks.
+    #[default]
+    Mask,
+    /// Each pixel is taken from whichever input is sharpest there; masks are ignored.
+    Focus,
+    /// The masked blend is composited over input `base` using the soft-light blend mode.
+    SoftLight { base: usize },
+    /// Each channel of each pixel is the `p`th percentile, from 0 to 1, of the inputs' values:
+    /// 0 keeps the darkest, 0.5 the median and 1 the brightest. Masks are ignored.
+    Percentile { p: f64 },
+    /// Each channel of each pixel is the mean of the inputs' values after `iterations` passes of
+    /// discarding those more than `sigma` standard deviations from the mean, removing hot pixels
+    /// and satellite trails. Masks are ignored.
+    SigmaClip { sigma: f64, iterations: u32 },
+    /// Each channel of each pixel is the largest difference between consecutive inputs, to show
+    /// where there's motion. Masks are ignored.
+    Difference,
+    /// A weighted average of the whole inputs, with weights following `dissolve_schedule` over
+    /// the sequence, for a ghostly cross-dissolve through time. Masks are ignored.
+    TimeDissolve,
+    /// Frame `i` of `N` is weighted by `decay` to the power `N - 1 - i`, so older frames fade
+    /// like a comet's tail behind the newest, which has full weight. `combine` picks how the
+    /// weighted frames are merged. Masks are ignored.
+    MotionTrail {
+        decay: f64,
+        #[serde(default)]
+        combine: TrailCombine,
+    },
+}
+
+impl BlendMode {
+    /// Whether the blend is weighted by the masks, so that they need preparing first.
+    fn uses_masks(self) -> bool {
+        matches!(self, BlendMode::Mask | BlendMode::SoftLight { .. })
+    }
+}
+
+/// How `BlendMode::MotionTrail` merges its weighted frames.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TrailCombine {
+    /// Each channel of each pixel is the largest weighted value, so bright trails stay sharp.
+    #[default]
+    Lighten,
+    /// Each channel of each pixel is the weighted average of the values.
+    Average,
+}
+
+/// How `BlendMode::TimeDissolve` weights each frame by its position in the sequence.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DissolveSchedule {
+    /// Weights rise from the first frame to a peak at the middle and fall again to the last.
+    #[default]
+    Triangular,
+    /// Weights rise steadily from the first frame to the last, so later frames dominate.
+    Linear,
+}
+
+/// The float type that blends and mask normalization accumulate in.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Precision {
+    /// `f32`, which is faster and differs from `Double` by at most 1 per channel.
+    Single,
+    #[default]
+    Double,
+}
+
+/// Which channel of a mask file gives its image's weight.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MaskChannel {
+    /// The Rec. 601 luminance, for masks painted in color.
+    Luminance,
+    /// The red channel, for masks painted in grayscale.
+    Red,
+    /// The alpha channel, for mattes exported from painting tools. Masks without one are opaque.
+    Alpha,
+}
+
+/// A step in preparing the masks, applied in the order given by `Pipeline::mask_ops`.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub enum MaskOp {
+    /// Replaces the masks with ones generated according to `generate_masks`.
+    Generate,
+    /// Reverses which mask is assigned to which image.
+    Reverse,
+    /// Inverts every mask.
+    Invert,
+    /// Blurs every mask with a Gaussian of the given standard deviation.
+    Blur(f32),
+    /// Scales masks by each image's local contrast.
+    ContrastWeight,
+    /// Scales each mask by its image's overall sharpness.
+    SharpnessWeight,
+    /// Scales the masks to sum to full weight at each pixel.
+    Normalize,
+    /// Normalizes the masks with a softmax of the given temperature.
+    NormalizeSoftmax(f64),
+    /// Gives the image at the given index whatever weight the masks leave unassigned.
+    Background(usize),
+}
+
+/// A rectangle of full weight in image `image`'s mask, spanning columns `x0..x1` and rows
+/// `y0..y1`. The rows default to the whole height.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BandSpec {
+    pub image: usize,
+    pub x0: u32,
+    pub x1: u32,
+    pub y0: Option<u32>,
+    pub y1: Option<u32>,
+}
+
+/// The order in which input images are assigned to bands, applied before masks are generated.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub enum SortKey {
+    /// By path.
+    Filename,
+    /// By the EXIF DateTimeOriginal field. If any image lacks one, all are sorted by path instead.
+    ExifDateTime,
+    /// By the files' last-modified times.
+    ModifiedTime,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct Pipeline {
     pub filenames: Vec<PipelineInputImage>,
-    pub generate_masks: Option<MaskType>,
+    pub blend_mode: Option<BlendMode>,
+    /// Accumulates the blend and mask normalization in `Single` or `Double` (the default)
+    /// precision. Blends of 16-bit inputs are always accumulated in `Double`, and `Single` can't be
+    /// streamed.
+    pub precision: Option<Precision>,
+    /// How the `TimeDissolve` blend mode weights frames. Defaults to `Triangular`.
+    pub dissolve_schedule: Option<DissolveSchedule>,
+    /// The mask type to generate for every image, or a list of one per image.
+    pub generate_masks: Option<MaskSpec>,
+    /// Gives `VerticalFlat` and `HorizontalFlat` masks partial coverage in pixels that a band's
+    /// edge passes through, smoothing seams that don't fall on pixel boundaries.
+    pub antialias: Option<bool>,
+    /// Per-band overrides of `generate_masks`' steepness: the `i`th generated mask uses `ks[i]`
+    /// instead of `k`. Must have one entry per image, and every mask type must have a `k`.
+    pub ks: Option<Vec<f64>>,
+    /// The width in pixels of the crossfade between generated logistic masks, from 90% to 10% of
+    /// the weight. When set, it replaces `generate_masks`' `k`, which is derived from it and the
+    /// band width with `k_for_seam_width`. Seams narrower than about a quarter of the band come out
+    /// sharper than asked.
+    pub seam_width: Option<f64>,
+    /// Builds masks from these rectangles instead of `generate_masks`. An image may have several
+    /// bands, and overlapping bands are shared out by `normalize_masks`.
+    pub bands: Option<Vec<BandSpec>>,
+    /// A multi-page TIFF whose `i`th page is used as the mask for image `i`, instead of mask
+    /// files on each input.
+    pub masks_tiff: Option<String>,
+    /// Only this `[x, y, width, height]` rectangle of the inputs is blended, and the output is
+    /// cropped to it. Generated masks span the region rather than the whole image.
+    pub region: Option<[u32; 4]>,
+    /// Resizes masks that don't match their image's dimensions with bilinear interpolation,
+    /// instead of failing. This allows masks to be painted at a lower resolution.
+    pub scale_masks_to_images: Option<bool>,
+    /// Deprecated alias of `mask_source: Luminance`, kept so older pipelines still load.
+    pub luminance_masks: Option<bool>,
+    /// Weights by a single channel of each mask file instead of each channel separately.
+    pub mask_source: Option<MaskChannel>,
+    /// Scales each image's brightness to match the median frame's, hiding exposure drift.
+    pub match_exposure: Option<bool>,
+    /// Shifts each image (and its mask) to line up with the first, for handheld sequences.
+    pub align: Option<bool>,
+    /// Standard deviation of a Gaussian blur applied to masks before they're normalized.
+    pub blur_masks: Option<f32>,
+    /// Scales masks by each image's local contrast, preserving detail where bands overlap.
+    pub contrast_weight: Option<bool>,
+    /// Scales each mask by its image's overall sharpness, so the crispest frames dominate.
+    pub weight_by_sharpness: Option<bool>,
     pub normalize_masks: Option<bool>,
+    /// Fades generated vertical or horizontal band masks to zero weight over this many pixels at
+    /// the first band's outer edge and the last band's, so the blend vignettes to black there.
+    /// Applied after the other mask options.
+    pub edge_fade: Option<u32>,
+    /// When set, `normalize_masks` uses `normalize_masks_softmax` with this temperature instead of
+    /// scaling masks linearly. Lower temperatures give harder seams. Requires `normalize_masks`,
+    /// and can't be streamed.
+    pub softmax_temperature: Option<f64>,
+    /// Mask operations applied in order, such as `["Generate", {"Blur": 5.0}, "Normalize"]`.
+    /// When set, this replaces the order and effect of `reverse`, `blur_masks`,
+    /// `contrast_weight`, `weight_by_sharpness`, `normalize_masks` and `background`.
+    pub mask_ops: Option<Vec<MaskOp>>,
+    /// Index of an image that fills in whatever weight the masks leave unassigned, so that
+    /// uncovered pixels show it instead of black.
+    pub background: Option<usize>,
     pub save_masks: Option<bool>,
+    /// Appended to each image's file stem to name its saved mask. Defaults to `"_mask"`.
+    pub mask_suffix: Option<String>,
+    /// The directory masks are saved in. Defaults to each image's own directory.
+    pub mask_output_dir: Option<String>,
+    /// A directory where generated masks are saved and, on later runs with the same mask types,
+    /// dimensions and image count, loaded from instead of being regenerated.
+    pub mask_cache_dir: Option<String>,
+    /// Reverses the order in which generated masks are assigned to images. This has no effect
+    /// on user-supplied masks.
+    pub reverse: Option<bool>,
+    /// Reorders the input images, along with their masks, instead of using the order they're
+    /// listed in.
+    pub sort_by: Option<SortKey>,
+    /// Computes generated masks on the fly while blending instead of storing them.
+    pub stream: Option<bool>,
+    pub save: String,
+    /// The output format, given as an extension such as `"png"`. Defaults to the format implied
+    /// by `save`'s extension.
+    pub format: Option<String>,
+    /// Per-channel gamma applied to the blended image, as `255 * (value / 255) ^ (1 / gamma)`.
+    /// Values above 1 brighten a channel and values below 1 darken it.
+    pub output_gamma: Option<[f64; 3]>,
+    /// A `.cube` 3D lookup table applied to the output after compositing and `output_gamma`.
+    pub lut: Option<String>,
+    /// Saves the output as a single-channel luminance image.
+    pub grayscale: Option<bool>,
+    /// Quality from 1 to 100 used when the output is a JPEG or WebP. WebP output is lossless
+    /// unless this is set.
+    #[serde(alias = "jpeg_quality")]
+    pub quality: Option<u8>,
+    /// The compression level used when the output is a PNG: `Fast`, `Default` or `Best`.
+    pub png_compression: Option<String>,
+    /// Copies camera metadata such as orientation, camera model and capture time from the first
+    /// input to the output, if the output is a JPEG or PNG.
+    pub preserve_exif: Option<bool>,
+    /// Tags the output's color space: `"sRGB"`, or the path of an ICC profile to embed. PNGs
+    /// support both and JPEGs support ICC profiles; other formats get a warning instead.
+    pub color_profile: Option<String>,
+    /// Where to save a false-color map showing which image dominates each pixel.
+    pub debug_map: Option<String>,
+    /// Fails the run if anything would otherwise only produce a warning, such as uncovered
+    /// pixels or resized masks.
+    pub strict: Option<bool>,
+    /// Drops inputs that fail to load, with a warning, instead of failing the run. At least one
+    /// image must still load.
+    pub skip_unreadable: Option<bool>,
+    /// Prints details of each image as it's loaded and the band it's assigned, and warns about
+    /// pixels no mask covers.
+    pub verbose: Option<bool>,
+}
+
+/// What a run reports to the callback given to `Pipeline::execute_with_progress`.
+#[derive(Debug)]
+pub enum Progress {
+    /// `done` of the `total` input files have been loaded.
+    Loaded { done: usize, total: usize },
+    /// A step that may take a while, such as compositing, has started.
+    Stage(&'static str),
+    /// A line of status output, which is printed instead when there's no callback.
+    Message(String),
+}
+
+/// What a successful run of a pipeline produced, as returned by `Pipeline::execute` and its
+/// variants.
+#[derive(Clone)]
+pub struct ExecuteOutcome {
+    /// Describes the output written, or `None` if only masks were saved.
+    pub report: Option<PipelineReport>,
+    /// Problems that didn't stop the run, such as resized masks, or uncovered pixels when
+    /// running verbosely or strictly.
+    pub warnings: Vec<String>,
+    /// How long each step of the run took.
+    pub timings: Timings,
+    /// Where masks were saved, if they were.
+    pub saved_masks: Vec<PathBuf>,
+}
+
+/// A summary of what a pipeline run actually did and produced.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PipelineReport {
+    pub inputs: Vec<ReportedImage>,
+    pub blend_mode: BlendMode,
+    /// The mask type generated for every image, or `None` if masks weren't generated or were
+    /// given per image.
+    pub mask_type: Option<MaskType>,
+    /// The mask types `generate_masks` listed: the shared one, or one per image.
+    #[serde(default)]
+    pub mask_types: Vec<MaskType>,
+    pub normalized: bool,
+    pub output: ReportedImage,
+    /// The size in bytes of the output file.
+    pub output_bytes: u64,
+}
+
+/// How long each step of a pipeline run took, in milliseconds. Steps that didn't run are `None`.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Timings {
+    pub load_ms: Option<f64>,
+    pub generate_ms: Option<f64>,
+    pub normalize_ms: Option<f64>,
+    pub save_masks_ms: Option<f64>,
+    /// Blending and writing the output.
+    pub save_ms: Option<f64>,
+}
+
+/// An image's path and dimensions, as recorded in a `PipelineReport`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReportedImage {
+    pub path: PathBuf,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A pipeline's settings with their defaults filled in, as returned by `Pipeline::resolved`.
+/// Settings that remain `None` are disabled rather than defaulted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ResolvedPipeline {
+    pub filenames: Vec<PipelineInputImage>,
+    pub blend_mode: BlendMode,
+    pub precision: Precision,
+    pub dissolve_schedule: DissolveSchedule,
+    pub generate_masks: Option<MaskSpec>,
+    pub antialias: bool,
+    pub ks: Option<Vec<f64>>,
+    pub seam_width: Option<f64>,
+    pub bands: Option<Vec<BandSpec>>,
+    pub masks_tiff: Option<String>,
+    pub region: Option<[u32; 4]>,
+    pub scale_masks_to_images: bool,
+    pub mask_source: Option<MaskChannel>,
+    pub match_exposure: bool,
+    pub align: bool,
+    pub edge_fade: Option<u32>,
+    /// The mask operations applied, in order, whether from `mask_ops` or derived from the
+    /// individual mask options.
+    pub mask_ops: Vec<MaskOp>,
+    pub save_masks: bool,
+    pub mask_suffix: String,
+    pub mask_output_dir: Option<String>,
+    pub mask_cache_dir: Option<String>,
+    pub sort_by: Option<SortKey>,
+    pub stream: bool,
     pub save: String,
+    /// The output format's extension, such as `"png"`.
+    pub format: String,
+    pub output_gamma: [f64; 3],
+    pub lut: Option<String>,
+    pub grayscale: bool,
+    /// The quality JPEG and lossy WebP output is encoded with. It's `None` for lossless WebP and
+    /// formats without a quality setting.
+    pub quality: Option<u8>,
+    pub png_compression: String,
+    pub preserve_exif: bool,
+    pub color_profile: Option<String>,
+    pub debug_map: Option<String>,
+    pub strict: bool,
+    pub skip_unreadable: bool,
+    pub verbose: bool,
 }
 
 impl Pipeline {
+    /// Writes an example pipeline as TOML or YAML if `destination` has a `.toml` or
+    /// `.yaml`/`.yml` extension, and as JSON otherwise.
     pub fn save_example<P: AsRef<Path>>(
         destination: P,
         images: Option<Vec<String>>,
     ) -> Result<(), std::io::Error> {
+        let example = Self::example(images);
+        let extension = destination
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        let contents = match extension.as_deref() {
+            Some("toml") => toml::to_string_pretty(&example).unwrap(),
+            Some("yaml" | "yml") => example.to_yaml().unwrap(),
+            _ => serde_json::to_string_pretty(&example).unwrap(),
+        };
+
+        std::fs::write(destination, contents)?;
+
+        Ok(())
+    }
+
+    /// Writes this pipeline to `destination` so it can be loaded again, choosing the format by
+    /// extension as `save_example` does: TOML or YAML if so named, and pretty JSON otherwise.
+    pub fn save<P: AsRef<Path>>(&self, destination: P) -> Result<(), HdtrError> {
+        let extension = destination
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+
+        let contents = match extension.as_deref() {
+            Some("toml") => toml::to_string_pretty(self).map_err(|e| e.to_string())?,
+            Some("yaml" | "yml") => self.to_yaml()?,
+            _ => serde_json::to_string_pretty(self)?,
+        };
+
+        std::fs::write(destination, contents)?;
+
+        Ok(())
+    }
+
+    /// Parses a YAML pipeline. Enums such as `MaskType` are written as single-entry mappings,
+    /// e.g. `generate_masks: {VerticalLogistic: {k: 0.01}}`, just as in JSON.
+    pub fn from_yaml(yaml: &str) -> Result<Self, HdtrError> {
+        let deserializer = serde_yaml::Deserializer::from_str(yaml);
+        Ok(serde_yaml::with::singleton_map_recursive::deserialize(
+            deserializer,
+        )?)
+    }
+
+    pub fn to_yaml(&self) -> Result<String, HdtrError> {
+        let mut yaml = Vec::new();
+        let mut serializer = serde_yaml::Serializer::new(&mut yaml);
+        serde_yaml::with::singleton_map_recursive::serialize(self, &mut serializer)?;
+        Ok(String::from_utf8(yaml).expect("YAML output is UTF-8"))
+    }
+
+    fn example(images: Option<Vec<String>>) -> Self {
         let filenames = match images {
             Some(fs) => fs.into_iter().map(|f| f.into()).collect(),
             None => vec![
@@ -86,19 +775,31 @@ impl Pipeline {
             ],
         };
 
-        let ex = Pipeline {
+        Pipeline {
             filenames,
-            generate_masks: Some(MaskType::VerticalLogistic { k: 0.01 }),
+            generate_masks: Some(MaskType::VerticalLogistic { k: 0.01 }.into()),
             normalize_masks: Some(true),
             save_masks: Some(false),
             save: "blended.png".to_string(),
-        };
-
-        let json = serde_json::to_string_pretty(&ex).unwrap();
+            ..Default::default()
+        }
+    }
 
-        std::fs::write(destination, json)?;
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
 
-        Ok(())
+    /// The local files and directories the pipeline reads: each input and its mask, and
+    /// `masks_tiff` and `lut` if set. URLs are left out.
+    pub fn input_paths(&self) -> Vec<PathBuf> {
+        self.filenames
+            .iter()
+            .flat_map(|f| std::iter::once(&f.image).chain(&f.mask))
+            .chain(&self.masks_tiff)
+            .chain(&self.lut)
+            .filter(|path| !is_url(path))
+            .map(PathBuf::from)
+            .collect()
     }
 
     /// Validates that the pipeline seems okay
@@ -108,111 +809,2327 @@ impl Pipeline {
         }
 
         for file in &self.filenames {
-            if !std::path::Path::new(&file.image).exists() {
+            // URLs are only checked when they're downloaded.
+            if !is_url(&file.image) && !std::path::Path::new(&file.image).exists() {
                 return Err(HdtrError::InputFileDoesNotExist(file.image.to_string()));
             }
 
             if let Some(mask) = &file.mask {
-                if !std::path::Path::new(mask).exists() {
+                if !is_url(mask) && !std::path::Path::new(mask).exists() {
                     return Err(HdtrError::InputFileDoesNotExist(mask.to_string()));
                 }
+
+                if file.expands() {
+                    return Err(HdtrError::PipelineError(
+                        format!(
+                            "{} expands into several images and cannot have a mask",
+                            file.image
+                        )
+                        .into(),
+                    ));
+                }
+            }
+
+            if Path::new(&file.image).is_dir() {
+                directory_images(Path::new(&file.image))?;
             }
         }
 
-        Ok(())
-    }
+        if let Some(masks_tiff) = &self.masks_tiff {
+            if !Path::new(masks_tiff).exists() {
+                return Err(HdtrError::InputFileDoesNotExist(masks_tiff.to_string()));
+            }
 
-    pub fn execute(&self) -> Result<(), HdtrError> {
-        self.validate()?;
+            if self.filenames.iter().any(|f| f.mask.is_some()) {
+                return Err(HdtrError::PipelineError(
+                    "masks_tiff cannot be used with per-image mask files".into(),
+                ));
+            }
+        }
 
-        let s = std::time::Instant::now();
-        let it = self.filenames.iter().enumerate().collect::<Vec<_>>();
-        let mut loaded = it
-            .into_par_iter()
-            .map(|(idx, filename)| filename.load().map(|img_mask| (idx, img_mask)))
-            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(profile) = &self.color_profile {
+            ColorProfile::load(profile)?;
+        }
 
-        println!("Loaded {} images in {:?}", loaded.len(), s.elapsed());
+        if let Some(lut) = &self.lut {
+            if !Path::new(lut).exists() {
+                return Err(HdtrError::InputFileDoesNotExist(lut.to_string()));
+            }
+            Lut::from_cube(lut)?;
+        }
 
-        loaded.sort_by_key(|(idx, _)| *idx);
+        let format = self.output_format()?;
+        if !SUPPORTED_OUTPUT_FORMATS.contains(&format) {
+            return Err(HdtrError::PipelineError(
+                format!(
+                    "Can't save {} as {format:?}; supported extensions are {}",
+                    self.save,
+                    SUPPORTED_OUTPUT_FORMATS
+                        .iter()
+                        .map(|f| f.extensions_str()[0])
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+                .into(),
+            ));
+        }
 
-        let mut it = loaded.into_iter().map(|(_, img_mask)| img_mask);
+        if let Some([_, _, w, h]) = self.region {
+            if w == 0 || h == 0 {
+                return Err(HdtrError::PipelineError(
+                    "region width and height must be positive".into(),
+                ));
+            }
+        }
 
-        let (im, m) = it
-            .next()
-            .ok_or(HdtrError::HDTR("No images were loaded".into()))?;
+        if let Some(gamma) = self.output_gamma {
+            if gamma.iter().any(|&g| g <= 0.) {
+                return Err(HdtrError::PipelineError(
+                    "output_gamma values must be positive".into(),
+                ));
+            }
+        }
 
-        let expected = im.im.dimensions();
+        self.png_compression()?;
 
-        let mut images = vec![im];
-        let mut masks = vec![m];
+        if let Some(quality) = self.quality {
+            if !(1..=100).contains(&quality) {
+                return Err(HdtrError::PipelineError(
+                    "quality must be between 1 and 100".into(),
+                ));
+            }
+        }
 
-        for (img, mask) in it {
-            let received = img.im.dimensions();
+        // GIFs and directories expand into more images, so their count is checked once loaded.
+        if !self.filenames.iter().any(|f| f.expands()) {
+            self.check_per_image_counts(self.filenames.len())?;
 
-            if expected != received {
-                return Err(HdtrError::DimensionMismatch {
-                    expected,
-                    received,
-                    details: "Image has different dimensions than expected".into(),
-                });
+            if let Some(BlendMode::SoftLight { base }) = self.blend_mode {
+                check_index("SoftLight base", base, self.filenames.len())?;
             }
 
-            images.push(img);
-            masks.push(mask);
+            if let Some(background) = self.background {
+                check_index("background", background, self.filenames.len())?;
+            }
         }
 
-        let mut images = InputImages {
-            images,
-            masks,
-            width: expected.0,
-            height: expected.1,
-        };
+        if let Some(BlendMode::Percentile { p }) = self.blend_mode {
+            if !(0. ..=1.).contains(&p) {
+                return Err(HdtrError::PipelineError(
+                    "Percentile p must be between 0 and 1".into(),
+                ));
+            }
+        }
 
-        if let Some(mask_type) = self.generate_masks {
-            let s = std::time::Instant::now();
-            images.generate_masks(mask_type);
-            println!(
-                "Generated {} masks in {:?}",
-                images.masks.len(),
-                s.elapsed()
-            );
+        if let Some(BlendMode::MotionTrail { decay, .. }) = self.blend_mode {
+            if !(decay > 0. && decay <= 1.) {
+                return Err(HdtrError::PipelineError(
+                    "MotionTrail decay must be greater than 0 and at most 1".into(),
+                ));
+            }
         }
 
-        if self.normalize_masks == Some(true) {
-            let s = std::time::Instant::now();
-            images.normalize_masks();
-            println!("Normalized masks in {:?}", s.elapsed());
+        if let Some(BlendMode::SigmaClip { sigma, .. }) = self.blend_mode {
+            if sigma <= 0. {
+                return Err(HdtrError::PipelineError(
+                    "SigmaClip sigma must be positive".into(),
+                ));
+            }
         }
 
-        if self.save_masks == Some(true) {
-            let s = std::time::Instant::now();
-            images.save_masks()?;
-            println!("Saved masks in {:?}", s.elapsed());
+        for mask_type in self.generate_masks.iter().flat_map(MaskSpec::mask_types) {
+            match *mask_type {
+                MaskType::VerticalSine { wavelength, .. } if wavelength <= 0. => {
+                    return Err(HdtrError::PipelineError(
+                        "VerticalSine wavelength must be positive".into(),
+                    ));
+                }
+                MaskType::VerticalLogisticDithered { amplitude, .. } if amplitude < 0. => {
+                    return Err(HdtrError::PipelineError(
+                        "VerticalLogisticDithered amplitude must not be negative".into(),
+                    ));
+                }
+                MaskType::VerticalFlatFeather { overlap } if !(0. ..1.).contains(&overlap) => {
+                    return Err(HdtrError::PipelineError(
+                        "VerticalFlatFeather overlap must be at least 0 and less than 1".into(),
+                    ));
+                }
+                MaskType::Grid { cols, rows } if cols == 0 || rows == 0 => {
+                    return Err(HdtrError::PipelineError(
+                        "Grid cols and rows must be positive".into(),
+                    ));
+                }
+                _ => {}
+            }
         }
 
-        let s = std::time::Instant::now();
-        images.save(&self.save)?;
-        println!("Saved {} in {:?}", self.save, s.elapsed());
+        for op in self.mask_ops.iter().flatten() {
+            match *op {
+                MaskOp::Generate if self.generate_masks.is_none() && self.bands.is_none() => {
+                    return Err(HdtrError::PipelineError(
+                        "The Generate mask operation requires generate_masks or bands to be set"
+                            .into(),
+                    ));
+                }
+                MaskOp::Blur(sigma) if sigma <= 0. => {
+                    return Err(HdtrError::PipelineError(
+                        "Blur mask operations must be positive".into(),
+                    ));
+                }
+                MaskOp::NormalizeSoftmax(temperature) if temperature <= 0. => {
+                    return Err(HdtrError::PipelineError(
+                        "NormalizeSoftmax temperatures must be positive".into(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(temperature) = self.softmax_temperature {
+            if temperature <= 0. {
+                return Err(HdtrError::PipelineError(
+                    "softmax_temperature must be positive".into(),
+                ));
+            }
+
+            if self.normalize_masks != Some(true) {
+                return Err(HdtrError::PipelineError(
+                    "softmax_temperature requires normalize_masks".into(),
+                ));
+            }
+        }
+
+        if self.edge_fade == Some(0) {
+            return Err(HdtrError::PipelineError(
+                "edge_fade must be positive".into(),
+            ));
+        }
+
+        if let (Some(true), Some(channel @ (MaskChannel::Red | MaskChannel::Alpha))) =
+            (self.luminance_masks, self.mask_source)
+        {
+            return Err(HdtrError::PipelineError(
+                format!("luminance_masks conflicts with mask_source {channel:?}").into(),
+            ));
+        }
+
+        if let Some(bands) = &self.bands {
+            if let Some(band) = bands
+                .iter()
+                .find(|b| b.x0 >= b.x1 || b.y0.unwrap_or(0) >= b.y1.unwrap_or(u32::MAX))
+            {
+                return Err(HdtrError::PipelineError(
+                    format!("Band {band:?} must have x0 < x1 and y0 < y1").into(),
+                ));
+            }
+        }
+
+        if self.ks.is_some() {
+            let mask_types = self.generate_masks.as_ref().map(MaskSpec::mask_types);
+            if let Some(mask_type) = mask_types.unwrap_or_default().iter().find(|m| !m.has_k()) {
+                return Err(HdtrError::PipelineError(
+                    format!("ks cannot be used with {mask_type:?} masks, which have no k").into(),
+                ));
+            }
+        }
+
+        if let Some(seam_width) = self.seam_width {
+            if seam_width <= 0. {
+                return Err(HdtrError::PipelineError(
+                    "seam_width must be positive".into(),
+                ));
+            }
+
+            if self.ks.is_some() {
+                return Err(HdtrError::PipelineError(
+                    "seam_width cannot be used with ks".into(),
+                ));
+            }
+        }
+
+        if let Some(sigma) = self.blur_masks {
+            if sigma <= 0. {
+                return Err(HdtrError::PipelineError(
+                    "blur_masks must be positive".into(),
+                ));
+            }
+        }
+
+        if self.stream == Some(true) {
+            if !matches!(self.blend_mode, None | Some(BlendMode::Mask)) {
+                return Err(HdtrError::PipelineError(
+                    "stream can only be used with the Mask blend mode".into(),
+                ));
+            }
+
+            if self.generate_masks.is_none() {
+                return Err(HdtrError::PipelineError(
+                    "stream requires generate_masks to be set".into(),
+                ));
+            }
+
+            // Options that need stored masks
+            let conflicts = [
+                (
+                    "mask files",
+                    self.filenames.iter().any(|f| f.mask.is_some()),
+                ),
+                ("masks_tiff", self.masks_tiff.is_some()),
+                ("save_masks", self.save_masks == Some(true)),
+                ("blur_masks", self.blur_masks.is_some()),
+                ("debug_map", self.debug_map.is_some()),
+                ("contrast_weight", self.contrast_weight == Some(true)),
+                (
+                    "weight_by_sharpness",
+                    self.weight_by_sharpness == Some(true),
+                ),
+                ("mask_ops", self.mask_ops.is_some()),
+                ("bands", self.bands.is_some()),
+                ("mask_cache_dir", self.mask_cache_dir.is_some()),
+                ("edge_fade", self.edge_fade.is_some()),
+                ("background", self.background.is_some()),
+                ("softmax_temperature", self.softmax_temperature.is_some()),
+                (
+                    "Single precision",
+                    self.precision == Some(Precision::Single),
+                ),
+            ];
+
+            if let Some((option, _)) = conflicts.iter().find(|(_, set)| *set) {
+                return Err(HdtrError::PipelineError(
+                    format!("stream cannot be used with {option}").into(),
+                ));
+            }
+        }
 
         Ok(())
     }
-}
 
-#[test]
-fn test_save_example() {
-    Pipeline::save_example("test_pipeline.json", None).unwrap();
-}
+    /// Checks that `ks` and per-image `generate_masks` have one entry for each of `image_count`
+    /// images.
+    fn check_per_image_counts(&self, image_count: usize) -> Result<(), HdtrError> {
+        let mask_types = match &self.generate_masks {
+            Some(MaskSpec::PerImage(mask_types)) => Some(mask_types.len()),
+            _ => None,
+        };
 
-fn default_mask((width, height): (u32, u32)) -> DynamicImage {
-    let mut canvas = RgbImage::new(width, height);
-    let black = *Pixel::from_slice(&[0, 0, 0]);
+        for (option, len) in [
+            ("ks", self.ks.as_ref().map(Vec::len)),
+            ("generate_masks", mask_types),
+        ] {
+            match len {
+                Some(len) if len != image_count => {
+                    return Err(HdtrError::PipelineError(
+                        format!("{option} has {len} entries but there are {image_count} images")
+                            .into(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn execute(&self) -> Result<ExecuteOutcome, HdtrError> {
+        self.run(RunOptions::default())
+    }
+
+    /// Runs the pipeline as `execute` does, but sends its progress to `callback` instead of
+    /// printing status lines, so that a caller can show a progress bar. Loading is reported per
+    /// file, from several threads.
+    pub fn execute_with_progress(
+        &self,
+        callback: impl Fn(Progress) + Sync,
+    ) -> Result<ExecuteOutcome, HdtrError> {
+        let mut run = Run::new(self);
+        run.progress = Some(&callback);
+        run.execute(RunOptions::default())?;
+        Ok(run.into_outcome())
+    }
+
+    fn run(&self, options: RunOptions) -> Result<ExecuteOutcome, HdtrError> {
+        let run = Run::new(self);
+        run.execute(options)?;
+        Ok(run.into_outcome())
+    }
+
+    /// Runs the pipeline on copies of the inputs downscaled so their longer edge is at most
+    /// `max_dim` pixels, writing the result next to `save` as `preview_<save>`. Masks are not
+    /// saved, since they would overwrite full-resolution ones.
+    pub fn execute_preview(&self, max_dim: u32) -> Result<ExecuteOutcome, HdtrError> {
+        if max_dim == 0 {
+            return Err(HdtrError::PipelineError(
+                "Preview dimension must be positive".into(),
+            ));
+        }
+
+        self.run(RunOptions {
+            preview: Some(max_dim),
+            ..Default::default()
+        })
+    }
+
+    /// Generates, processes and saves the masks as `execute` would, but doesn't blend the images.
+    /// Masks are saved regardless of `save_masks`.
+    pub fn execute_masks_only(&self) -> Result<ExecuteOutcome, HdtrError> {
+        if self.generate_masks.is_none() && self.bands.is_none() {
+            return Err(HdtrError::PipelineError(
+                "Saving only masks requires generate_masks or bands to be set".into(),
+            ));
+        }
+
+        self.run(RunOptions {
+            masks_only: true,
+            ..Default::default()
+        })
+    }
+
+    /// The settings a run uses, with every default filled in, for recording alongside the
+    /// output. An unrecognized output format is given as written.
+    pub fn resolved(&self) -> ResolvedPipeline {
+        let format = match self.output_format() {
+            Ok(format) => format.extensions_str()[0].to_string(),
+            Err(_) => self.format.clone().unwrap_or_default(),
+        };
+
+        ResolvedPipeline {
+            filenames: self.filenames.clone(),
+            blend_mode: self.blend_mode.unwrap_or_default(),
+            precision: self.precision.unwrap_or_default(),
+            dissolve_schedule: self.dissolve_schedule.unwrap_or_default(),
+            generate_masks: self.generate_masks.clone(),
+            antialias: self.antialias == Some(true),
+            ks: self.ks.clone(),
+            seam_width: self.seam_width,
+            bands: self.bands.clone(),
+            masks_tiff: self.masks_tiff.clone(),
+            region: self.region,
+            scale_masks_to_images: self.scale_masks_to_images == Some(true),
+            mask_source: self.mask_source(),
+            match_exposure: self.match_exposure == Some(true),
+            align: self.align == Some(true),
+            edge_fade: self.edge_fade,
+            mask_ops: self.mask_ops(),
+            save_masks: self.save_masks == Some(true),
+            mask_suffix: self
+                .mask_suffix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MASK_SUFFIX.into()),
+            mask_output_dir: self.mask_output_dir.clone(),
+            mask_cache_dir: self.mask_cache_dir.clone(),
+            sort_by: self.sort_by,
+            stream: self.stream == Some(true),
+            save: self.save.clone(),
+            format,
+            output_gamma: self.output_gamma.unwrap_or([1.; 3]),
+            lut: self.lut.clone(),
+            grayscale: self.grayscale == Some(true),
+            quality: self.quality(),
+            png_compression: self
+                .png_compression
+                .clone()
+                .unwrap_or_else(|| "Default".into()),
+            preserve_exif: self.preserve_exif == Some(true),
+            color_profile: self.color_profile.clone(),
+            debug_map: self.debug_map.clone(),
+            strict: self.strict == Some(true),
+            skip_unreadable: self.skip_unreadable == Some(true),
+            verbose: self.verbose == Some(true),
+        }
+    }
+
+    /// The mask operations `execute` applies in order: `mask_ops` if it's set, and otherwise those
+    /// implied by the individual mask options.
+    fn mask_ops(&self) -> Vec<MaskOp> {
+        if let Some(ops) = &self.mask_ops {
+            return ops.clone();
+        }
+
+        let flags = [
+            (
+                MaskOp::Generate,
+                self.generate_masks.is_some() || self.bands.is_some(),
+            ),
+            // Only generated masks are reversed; bands and mask files name their images.
+            (
+                MaskOp::Reverse,
+                self.reverse == Some(true) && self.generate_masks.is_some() && self.bands.is_none(),
+            ),
+            (
+                MaskOp::Blur(self.blur_masks.unwrap_or_default()),
+                self.blur_masks.is_some(),
+            ),
+            (MaskOp::ContrastWeight, self.contrast_weight == Some(true)),
+            (
+                MaskOp::SharpnessWeight,
+                self.weight_by_sharpness == Some(true),
+            ),
+            (
+                match self.softmax_temperature {
+                    Some(temperature) => MaskOp::NormalizeSoftmax(temperature),
+                    None => MaskOp::Normalize,
+                },
+                self.normalize_masks == Some(true),
+            ),
+            (
+                MaskOp::Background(self.background.unwrap_or_default()),
+                self.background.is_some(),
+            ),
+        ];
+
+        flags
+            .into_iter()
+            .filter(|(_, set)| *set)
+            .map(|(op, _)| op)
+            .collect()
+    }
+
+    /// Parses `png_compression`, if set.
+    fn png_compression(&self) -> Result<Option<CompressionType>, HdtrError> {
+        let Some(level) = &self.png_compression else {
+            return Ok(None);
+        };
+
+        match level.as_str() {
+            "Fast" => Ok(Some(CompressionType::Fast)),
+            "Default" => Ok(Some(CompressionType::Default)),
+            "Best" => Ok(Some(CompressionType::Best)),
+            _ => Err(HdtrError::PipelineError(
+                format!("png_compression must be Fast, Default or Best, not {level}").into(),
+            )),
+        }
+    }
+
+    /// The format of the blended image, from `format` if set and otherwise `save`'s extension.
+    fn output_format(&self) -> Result<ImageFormat, HdtrError> {
+        match &self.format {
+            Some(f) => ImageFormat::from_extension(f)
+                .ok_or_else(|| HdtrError::UnknownOutputFormat(f.to_string())),
+            None => format_from_path(Path::new(&self.save)),
+        }
+    }
+
+    /// The channel mask files are read by, with the deprecated `luminance_masks` resolved to
+    /// `Luminance`.
+    fn mask_source(&self) -> Option<MaskChannel> {
+        self.mask_source
+            .or((self.luminance_masks == Some(true)).then_some(MaskChannel::Luminance))
+    }
 
-    for x in 0..width {
-        for y in 0..height {
-            canvas.put_pixel(x, y, black);
+    /// The quality the output is encoded with, filling in the default for JPEGs.
+    fn quality(&self) -> Option<u8> {
+        match self.output_format() {
+            Ok(ImageFormat::Jpeg) => Some(self.quality.unwrap_or(DEFAULT_JPEG_QUALITY)),
+            Ok(ImageFormat::WebP) => self.quality,
+            _ => None,
         }
     }
 
-    DynamicImage::ImageRgb8(canvas)
+    /// Encodes `canvas` to `destination` as `format`, with the configured quality or compression.
+    fn write_canvas<P: PixelWithColorType<Subpixel = u8>>(
+        &self,
+        canvas: &ImageBuffer<P, Vec<u8>>,
+        destination: &Path,
+        format: ImageFormat,
+    ) -> Result<(), HdtrError> {
+        match (format, self.quality()) {
+            (ImageFormat::Jpeg, Some(quality)) => write_jpeg(canvas, destination, quality),
+            (ImageFormat::WebP, Some(quality)) => write_webp(canvas, destination, quality),
+            (ImageFormat::Png, _) if self.png_compression.is_some() => write_png(
+                canvas,
+                destination,
+                self.png_compression()?.unwrap_or_default(),
+                PngFilter::Adaptive,
+            ),
+            (format, _) => Ok(canvas.save_with_format(destination, format)?),
+        }
+    }
+
+    /// The files a run may write besides masks: the blended image, its preview and the debug map.
+    /// Masks depend on the inputs found, so they're given by `ExecuteOutcome::saved_masks`.
+    pub fn output_paths(&self) -> Vec<PathBuf> {
+        [self.destination(None), self.destination(Some(1))]
+            .into_iter()
+            .flatten()
+            .chain(self.debug_map.iter().map(PathBuf::from))
+            .collect()
+    }
+
+    /// The path the blended image is written to, which is prefixed with `preview_` for previews.
+    /// A preview never falls back to the output path itself, as that would overwrite the output.
+    fn destination(&self, preview: Option<u32>) -> Result<PathBuf, HdtrError> {
+        let save = Path::new(&self.save);
+        if preview.is_none() {
+            return Ok(save.to_path_buf());
+        }
+        match save.file_name() {
+            Some(file_name) => {
+                let mut name = std::ffi::OsString::from("preview_");
+                name.push(file_name);
+                Ok(save.with_file_name(name))
+            }
+            None => Err(HdtrError::PipelineError(
+                format!(
+                    "Can't name a preview for '{}' without overwriting it; give `save` a file name",
+                    self.save
+                )
+                .into(),
+            )),
+        }
+    }
+}
+
+/// One run of a pipeline, holding what the run produces so that the `Pipeline` itself stays
+/// plain configuration. It dereferences to the pipeline for its settings.
+struct Run<'a> {
+    pipeline: &'a Pipeline,
+    /// What the run produced, once its output has been written.
+    report: Mutex<Option<PipelineReport>>,
+    /// Problems that didn't stop the run.
+    warnings: Mutex<Vec<String>>,
+    /// How long the run's steps took.
+    timings: Mutex<Timings>,
+    /// Called with the run's progress instead of printing status lines.
+    progress: Option<&'a (dyn Fn(Progress) + Sync)>,
+    /// Where the run saved masks.
+    saved_masks: Mutex<Vec<PathBuf>>,
+    /// Whether a single input was copied without preparing its mask, so none is reported.
+    masks_skipped: AtomicBool,
+    /// The first input once sorted, which `preserve_exif` copies from even if `reverse` has
+    /// reordered the images since.
+    exif_source: Mutex<Option<PathBuf>>,
+}
+
+impl<'a> Run<'a> {
+    fn new(pipeline: &'a Pipeline) -> Self {
+        Self {
+            pipeline,
+            report: Mutex::default(),
+            warnings: Mutex::default(),
+            timings: Mutex::default(),
+            progress: None,
+            saved_masks: Mutex::default(),
+            masks_skipped: AtomicBool::default(),
+            exif_source: Mutex::default(),
+        }
+    }
+
+    /// Ends the run, returning what it produced.
+    fn into_outcome(self) -> ExecuteOutcome {
+        ExecuteOutcome {
+            report: self.report.into_inner().expect("Report lock poisoned"),
+            warnings: self.warnings.into_inner().expect("Warnings lock poisoned"),
+            timings: self.timings.into_inner().expect("Timings lock poisoned"),
+            saved_masks: self
+                .saved_masks
+                .into_inner()
+                .expect("Saved masks lock poisoned"),
+        }
+    }
+}
+
+impl Deref for Run<'_> {
+    type Target = Pipeline;
+
+    fn deref(&self) -> &Pipeline {
+        self.pipeline
+    }
+}
+
+impl Run<'_> {
+    /// The mask type used to generate each of `image_count` masks for images of `dimensions`.
+    fn band_mask_types(
+        &self,
+        spec: &MaskSpec,
+        image_count: usize,
+        (width, height): (u32, u32),
+    ) -> Vec<MaskType> {
+        let mask_types = match spec {
+            MaskSpec::Uniform(mask_type) => vec![*mask_type; image_count],
+            MaskSpec::PerImage(mask_types) => mask_types.clone(),
+        };
+
+        match (&self.ks, self.seam_width) {
+            (Some(ks), _) => mask_types
+                .iter()
+                .zip(ks)
+                .map(|(mask_type, &k)| mask_type.with_k(k))
+                .collect(),
+            (None, Some(seam_width)) => {
+                let k_for_length = |length: u32| {
+                    let k = k_for_seam_width(seam_width, length as f64 / image_count as f64);
+                    status!(self, "Using k = {k} for a {seam_width}px seam");
+                    k
+                };
+                let (mut k_x, mut k_y) = (None, None);
+                mask_types
+                    .iter()
+                    .map(|mask_type| {
+                        let k = match mask_type {
+                            MaskType::HorizontalLogistic { .. } => {
+                                *k_y.get_or_insert_with(|| k_for_length(height))
+                            }
+                            _ => *k_x.get_or_insert_with(|| k_for_length(width)),
+                        };
+                        mask_type.with_k(k)
+                    })
+                    .collect()
+            }
+            (None, None) => mask_types,
+        }
+    }
+
+    fn execute(&self, options: RunOptions) -> Result<(), HdtrError> {
+        self.validate()?;
+        let preview = options.preview;
+
+        if let (Some(true), Some(spec), false) =
+            (self.stream, &self.generate_masks, options.masks_only)
+        {
+            return self.execute_streaming(spec, preview);
+        }
+
+        let s = std::time::Instant::now();
+        let done = AtomicUsize::new(0);
+        let it = self.filenames.iter().enumerate().collect::<Vec<_>>();
+        let loaded = it
+            .into_par_iter()
+            .map(|(idx, filename)| {
+                let img_masks = filename.load_all(self).inspect(|img_masks| {
+                    for (img, _) in img_masks {
+                        self.log_loaded(img, filename.mask.as_deref());
+                    }
+                });
+                self.report_loaded(&done);
+                (idx, filename, img_masks)
+            })
+            .collect::<Vec<_>>();
+        let mut loaded = self.drop_failed_loads(loaded)?;
+
+        loaded.sort_by_key(|(idx, _)| *idx);
+
+        let mut loaded = loaded
+            .into_iter()
+            .flat_map(|(_, img_masks)| img_masks)
+            .collect::<Vec<_>>();
+        self.sort_inputs(&mut loaded, |(image, _)| image);
+        for (image, _) in &loaded {
+            image.ensure_nonempty()?;
+        }
+        self.set_exif_source(loaded.first().map(|(image, _)| image));
+
+        status!(self, "Loaded {} images in {:?}", loaded.len(), s.elapsed());
+        self.record_timing(|t| &mut t.load_ms, s.elapsed());
+
+        let mut it = loaded.into_iter();
+
+        let (im, m) = it
+            .next()
+            .ok_or(HdtrError::HDTR("No images were loaded".into()))?;
+
+        let expected = im.im.dimensions();
+
+        let mut images = vec![im];
+        let mut masks = vec![m];
+
+        for (img, mask) in it {
+            let received = img.im.dimensions();
+
+            if expected != received {
+                return Err(HdtrError::DimensionMismatch {
+                    expected,
+                    received,
+                    details: "Image has different dimensions than expected".into(),
+                });
+            }
+
+            images.push(img);
+            masks.push(mask);
+        }
+
+        if let Some(masks_tiff) = &self.masks_tiff {
+            let s = std::time::Instant::now();
+            let mut pages = read_tiff_pages(masks_tiff)?;
+            if pages.len() != images.len() {
+                return Err(HdtrError::PipelineError(
+                    format!(
+                        "{masks_tiff} has {} pages but there are {} images",
+                        pages.len(),
+                        images.len()
+                    )
+                    .into(),
+                ));
+            }
+
+            for (i, page) in pages.iter_mut().enumerate() {
+                let received = page.dimensions();
+                if expected != received && self.scale_masks_to_images == Some(true) {
+                    self.warn(format!(
+                        "Resized page {i} of {masks_tiff} from {}x{} to {}x{}",
+                        received.0, received.1, expected.0, expected.1
+                    ));
+                    *page = page.resize_exact(expected.0, expected.1, FilterType::Triangle);
+                } else if expected != received {
+                    return Err(HdtrError::DimensionMismatch {
+                        expected,
+                        received,
+                        details: format!("Page {i} of {masks_tiff} has different dimensions")
+                            .into(),
+                    });
+                }
+            }
+
+            masks = pages.into_iter().map(Some).collect();
+            status!(
+                self,
+                "Loaded {} masks from {masks_tiff} in {:?}",
+                masks.len(),
+                s.elapsed()
+            );
+        }
+
+        // Inputs without a mask file get an empty mask, which the channel options don't apply
+        // to: an alpha-less mask would otherwise read as fully opaque.
+        let mut masks = masks
+            .into_par_iter()
+            .map(|mask| match (mask, self.mask_source()) {
+                (Some(mask), Some(channel)) => channel_mask(&mask, channel),
+                (Some(mask), None) => mask,
+                (None, _) => default_mask(expected),
+            })
+            .collect::<Vec<_>>();
+
+        let expected = match self.region {
+            Some(region) => {
+                let [x, y, w, h] = check_region(region, expected)?;
+                images
+                    .par_iter_mut()
+                    .for_each(|img| img.im = img.im.crop_imm(x, y, w, h));
+                masks = masks.par_iter().map(|m| m.crop_imm(x, y, w, h)).collect();
+                (w, h)
+            }
+            None => expected,
+        };
+
+        let (width, height) = match preview {
+            Some(max_dim) => {
+                let dims = preview_dimensions(expected, max_dim);
+                downscale(&mut images, dims);
+                masks = masks
+                    .par_iter()
+                    .map(|m| m.resize_exact(dims.0, dims.1, FilterType::Triangle))
+                    .collect();
+                dims
+            }
+            None => expected,
+        };
+
+        let mut images = InputImages {
+            images,
+            masks,
+            width,
+            height,
+        };
+
+        self.match_exposure(&mut images);
+        self.align(&mut images);
+
+        let destination = self.destination(preview)?;
+
+        self.progress(Progress::Stage(match options.masks_only {
+            true => "Preparing masks",
+            false => "Compositing",
+        }));
+
+        // A single image blends to itself, so the mask work is skipped unless its results are
+        // saved or another blend mode needs it.
+        let blend_mode = self.blend_mode.unwrap_or_default();
+        let copy_single = images.len() == 1
+            && matches!(blend_mode, BlendMode::Mask)
+            && self.save_masks != Some(true)
+            && self.debug_map.is_none();
+        self.masks_skipped.store(copy_single, Ordering::Relaxed);
+
+        if options.masks_only || (blend_mode.uses_masks() && !copy_single) {
+            self.prepare_masks(&mut images, preview, options.masks_only)?;
+        }
+
+        if options.masks_only {
+            return Ok(());
+        }
+
+        let s = std::time::Instant::now();
+        let (canvas, action) = match blend_mode {
+            BlendMode::Focus => (Blended::Rgb8(images.focus_stack()), "Focus stacked".into()),
+            BlendMode::Percentile { p } => (
+                Blended::Rgb8(images.percentile_blend(p)),
+                format!("Blended at percentile {p}"),
+            ),
+            BlendMode::Difference => (
+                Blended::Rgb8(images.difference_map()),
+                "Mapped differences".into(),
+            ),
+            BlendMode::TimeDissolve => {
+                let schedule = self.dissolve_schedule.unwrap_or_default();
+                (
+                    Blended::Rgb8(images.time_dissolve(schedule)),
+                    "Dissolved".into(),
+                )
+            }
+            BlendMode::MotionTrail { decay, combine } => (
+                Blended::Rgb8(images.motion_trail(decay, combine)),
+                "Blended motion trail".into(),
+            ),
+            BlendMode::SigmaClip { sigma, iterations } => (
+                Blended::Rgb8(images.sigma_clip_blend(sigma, iterations)),
+                "Sigma-clipped".into(),
+            ),
+            BlendMode::SoftLight { base } => {
+                check_index("SoftLight base", base, images.len())?;
+                (
+                    Blended::Rgb8(images.soft_light_blend(base)),
+                    "Soft-light blended".into(),
+                )
+            }
+            BlendMode::Mask if copy_single => {
+                status!(
+                    self,
+                    "Only one input image given; copying it to the output unchanged"
+                );
+                let image = &images.images[0].im;
+                let canvas = match images.is_high_bit_depth() {
+                    true => Blended::Rgb16(image.to_rgb16()),
+                    false => Blended::Rgb8(image.to_rgb8()),
+                };
+                (canvas, "Copied".into())
+            }
+            BlendMode::Mask if images.is_high_bit_depth() => {
+                (Blended::Rgb16(images.blend_to_image16()), "Blended".into())
+            }
+            BlendMode::Mask => {
+                let precision = self.precision.unwrap_or_default();
+                let canvas = images.blend_to_image_with_precision(precision);
+                (Blended::Rgb8(canvas), "Blended".into())
+            }
+        };
+
+        match &canvas {
+            Blended::Rgb8(canvas) => self.write_output(canvas, &destination, &images)?,
+            Blended::Rgb16(canvas) => self.write_output16(canvas, &destination, &images)?,
+        }
+        status!(
+            self,
+            "{action} and saved {} in {:?}",
+            destination.display(),
+            s.elapsed()
+        );
+        self.record_timing(|t| &mut t.save_ms, s.elapsed());
+
+        Ok(())
+    }
+
+    /// Applies the mask operations and the options that depend on the finished masks, such as
+    /// `debug_map` and `save_masks`.
+    fn prepare_masks(
+        &self,
+        images: &mut InputImages,
+        preview: Option<u32>,
+        masks_only: bool,
+    ) -> Result<(), HdtrError> {
+        for op in self.mask_ops() {
+            self.apply_mask_op(images, op)?;
+        }
+
+        if let Some(margin) = self.edge_fade {
+            let horizontal = |mask_type: &MaskType| match mask_type {
+                MaskType::VerticalFlat
+                | MaskType::VerticalFlatFeather { .. }
+                | MaskType::VerticalLogistic { .. }
+                | MaskType::VerticalLogisticDithered { .. } => Some(false),
+                MaskType::HorizontalFlat | MaskType::HorizontalLogistic { .. } => Some(true),
+                _ => None,
+            };
+
+            // Mixed mask types only have edges to fade if their bands all run the same way.
+            let horizontal_bands = match self.generate_masks.as_ref().map(MaskSpec::mask_types) {
+                Some([first, rest @ ..]) => horizontal(first).filter(|&h| {
+                    rest.iter()
+                        .all(|mask_type| horizontal(mask_type) == Some(h))
+                }),
+                _ => None,
+            };
+
+            match horizontal_bands {
+                Some(horizontal_bands) => {
+                    let s = std::time::Instant::now();
+                    images.fade_edges(margin, horizontal_bands);
+                    status!(self, "Faded mask edges in {:?}", s.elapsed());
+                }
+                None => self.warn(
+                    "edge_fade only applies to generated vertical or horizontal bands".into(),
+                ),
+            }
+        }
+
+        if let Some(debug_map) = &self.debug_map {
+            let s = std::time::Instant::now();
+            images.debug_contribution_map_labeled().save(debug_map)?;
+            status!(
+                self,
+                "Saved contribution map {debug_map} in {:?}",
+                s.elapsed()
+            );
+        }
+
+        // Checking every pixel of every mask is slow, so it's only done when someone will see it.
+        if self.verbose == Some(true) || self.strict == Some(true) {
+            let coverage = images.coverage_report();
+            if let Some((x, y, w, h)) = coverage.bounding_box {
+                self.warn(format!(
+                    "{} pixels in the {w}x{h} region at ({x}, {y}) are not covered by any mask",
+                    coverage.uncovered
+                ));
+            }
+        }
+
+        if masks_only {
+            self.check_warnings()?;
+        }
+
+        if masks_only || (self.save_masks == Some(true) && preview.is_none()) {
+            let s = std::time::Instant::now();
+            let dir = self.mask_output_dir.as_deref().map(Path::new);
+            let suffix = self.mask_suffix.as_deref().unwrap_or(DEFAULT_MASK_SUFFIX);
+            images.save_masks_to(dir, suffix)?;
+            *self.saved_masks.lock().expect("Saved masks lock poisoned") =
+                images.mask_paths_to(dir, suffix)?;
+            status!(self, "Saved masks in {:?}", s.elapsed());
+            self.record_timing(|t| &mut t.save_masks_ms, s.elapsed());
+        }
+
+        Ok(())
+    }
+
+    /// Reorders `items` according to `sort_by`, using `image` to get each one's input image, and
+    /// logs the resulting order. Ties are broken by path, then by the original order.
+    fn sort_inputs<T>(&self, items: &mut Vec<T>, image: impl Fn(&T) -> &InputImage) {
+        let Some(mut sort_by) = self.sort_by else {
+            return;
+        };
+
+        let mut capture_times = match sort_by {
+            SortKey::ExifDateTime => items
+                .iter()
+                .map(|item| metadata::capture_time(&image(item).path))
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        if let (SortKey::ExifDateTime, None) = (sort_by, &capture_times) {
+            self.warn("Not every image has an EXIF capture time, so sorting by filename".into());
+            sort_by = SortKey::Filename;
+        }
+
+        let mut keyed = items
+            .drain(..)
+            .enumerate()
+            .map(|(i, item)| {
+                let path = image(&item).path.clone();
+                let capture_time = capture_times.as_mut().map(|t| std::mem::take(&mut t[i]));
+                let modified = match sort_by {
+                    SortKey::ModifiedTime => {
+                        std::fs::metadata(&path).and_then(|m| m.modified()).ok()
+                    }
+                    _ => None,
+                };
+                ((capture_time, modified, path), item)
+            })
+            .collect::<Vec<_>>();
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        items.extend(keyed.into_iter().map(|(_, item)| item));
+
+        status!(self, "Sorted images by {sort_by:?}:");
+        for item in items.iter() {
+            status!(self, "    {}", image(item).path.display());
+        }
+    }
+
+    fn apply_mask_op(&self, images: &mut InputImages, op: MaskOp) -> Result<(), HdtrError> {
+        let s = std::time::Instant::now();
+        match op {
+            MaskOp::Generate if self.bands.is_some() => {
+                let bands = self.bands.as_deref().unwrap_or_default();
+                for band in bands {
+                    check_index("Band image", band.image, images.len())?;
+                }
+                images.set_band_masks(bands);
+                status!(
+                    self,
+                    "Built {} masks from {} bands in {:?}",
+                    images.masks.len(),
+                    bands.len(),
+                    s.elapsed()
+                );
+                self.record_timing(|t| &mut t.generate_ms, s.elapsed());
+            }
+            MaskOp::Generate => {
+                let spec = self
+                    .generate_masks
+                    .as_ref()
+                    .ok_or(HdtrError::PipelineError(
+                        "The Generate mask operation requires generate_masks to be set".into(),
+                    ))?;
+                self.check_per_image_counts(images.images.len())?;
+                let mask_types =
+                    self.band_mask_types(spec, images.images.len(), (images.width, images.height));
+                let cache = self.mask_cache_dir.as_ref().map(|dir| {
+                    Path::new(dir).join(mask_cache_key(
+                        &mask_types,
+                        self.antialias == Some(true),
+                        (images.width, images.height),
+                    ))
+                });
+
+                match cache
+                    .as_deref()
+                    .and_then(|dir| read_cached_masks(dir, &mask_types))
+                {
+                    Some(masks) => {
+                        images.masks = masks;
+                        status!(
+                            self,
+                            "Loaded {} cached masks in {:?}",
+                            images.masks.len(),
+                            s.elapsed()
+                        );
+                    }
+                    None => {
+                        images.generate_masks(&mask_types, self.antialias == Some(true));
+                        if let Some(dir) = &cache {
+                            write_cached_masks(dir, &images.masks)?;
+                        }
+                        status!(
+                            self,
+                            "Generated {} masks in {:?}",
+                            images.masks.len(),
+                            s.elapsed()
+                        );
+                    }
+                }
+                // Log the bands the images end up with, after any reversal.
+                let count = images.images.len();
+                let reversed = self
+                    .mask_ops()
+                    .iter()
+                    .filter(|op| matches!(op, MaskOp::Reverse))
+                    .count()
+                    % 2
+                    == 1;
+                let bands = (0..count).map(|i| if reversed { count - 1 - i } else { i });
+                self.log_bands(&images.images, bands, &mask_types);
+                self.record_timing(|t| &mut t.generate_ms, s.elapsed());
+            }
+            MaskOp::Reverse => {
+                images.reverse_masks();
+                status!(self, "Reversed the order of the masks");
+            }
+            MaskOp::Invert => {
+                images.invert_all_masks();
+                status!(self, "Inverted masks in {:?}", s.elapsed());
+            }
+            MaskOp::Blur(sigma) => {
+                images.blur_masks(sigma);
+                status!(self, "Blurred masks in {:?}", s.elapsed());
+            }
+            MaskOp::ContrastWeight => {
+                images.apply_contrast_weights();
+                status!(self, "Weighted masks by contrast in {:?}", s.elapsed());
+            }
+            MaskOp::SharpnessWeight => {
+                let weights = images.apply_sharpness_weights();
+                for (input, weight) in images.images.iter().zip(weights) {
+                    status!(
+                        self,
+                        "Sharpness weight of {}: {weight:.3}",
+                        input.path.display()
+                    );
+                }
+                status!(self, "Weighted masks by sharpness in {:?}", s.elapsed());
+            }
+            MaskOp::Normalize => {
+                images.normalize_masks_with_precision(self.precision.unwrap_or_default());
+                status!(self, "Normalized masks in {:?}", s.elapsed());
+                self.record_timing(|t| &mut t.normalize_ms, s.elapsed());
+            }
+            MaskOp::NormalizeSoftmax(temperature) => {
+                images.normalize_masks_softmax(temperature);
+                status!(
+                    self,
+                    "Normalized masks with temperature {temperature} in {:?}",
+                    s.elapsed()
+                );
+                self.record_timing(|t| &mut t.normalize_ms, s.elapsed());
+            }
+            MaskOp::Background(background) => {
+                check_index("background", background, images.len())?;
+                images.fill_with_background(background);
+                status!(
+                    self,
+                    "Filled uncovered weight with background in {:?}",
+                    s.elapsed()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unwraps the result of loading each entry. With `skip_unreadable` set, entries that failed
+    /// are reported and dropped; otherwise the first failure is returned.
+    fn drop_failed_loads<T>(
+        &self,
+        results: Vec<(usize, &PipelineInputImage, Result<T, HdtrError>)>,
+    ) -> Result<Vec<(usize, T)>, HdtrError> {
+        let mut loaded = Vec::with_capacity(results.len());
+        let mut skipped = Vec::new();
+
+        for (idx, filename, result) in results {
+            match result {
+                Ok(images) => loaded.push((idx, images)),
+                Err(e) if self.skip_unreadable == Some(true) => {
+                    status!(self, "Skipping {}: {e:?}", filename.image);
+                    skipped.push(filename.image.as_str());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !skipped.is_empty() {
+            self.warn(format!(
+                "Skipped {} unreadable files: {}",
+                skipped.len(),
+                skipped.join(", ")
+            ));
+        }
+
+        Ok(loaded)
+    }
+
+    /// Reports that another input file has been loaded, where `done` counts those loaded so far.
+    fn report_loaded(&self, done: &AtomicUsize) {
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.progress(Progress::Loaded {
+            done,
+            total: self.filenames.len(),
+        });
+    }
+
+    /// With `verbose` set, prints `image`'s path and dimensions, and the mask file it was loaded
+    /// with, if any.
+    fn log_loaded(&self, image: &InputImage, mask: Option<&str>) {
+        if self.verbose == Some(true) {
+            let (width, height) = image.im.dimensions();
+            match mask {
+                Some(mask) => status!(
+                    self,
+                    "Loaded {} ({width}x{height}) with mask {mask}",
+                    image.path.display()
+                ),
+                None => status!(self, "Loaded {} ({width}x{height})", image.path.display()),
+            }
+        }
+    }
+
+    /// With `verbose` set, prints the band and generated mask type assigned to each image, where
+    /// `bands` gives each image's band in turn.
+    fn log_bands(
+        &self,
+        images: &[InputImage],
+        bands: impl Iterator<Item = usize>,
+        mask_types: &[MaskType],
+    ) {
+        if self.verbose == Some(true) {
+            for (image, band) in images.iter().zip(bands) {
+                status!(
+                    self,
+                    "{}: band {} of {}, {:?}",
+                    image.path.display(),
+                    band + 1,
+                    images.len(),
+                    mask_types[band]
+                );
+            }
+        }
+    }
+
+    /// Records that the step selected by `step` took `elapsed`.
+    fn record_timing(&self, step: fn(&mut Timings) -> &mut Option<f64>, elapsed: Duration) {
+        let mut timings = self.timings.lock().expect("Timings lock poisoned");
+        *step(&mut timings) = Some(elapsed.as_secs_f64() * 1000.);
+    }
+
+    /// Passes `progress` to the progress callback, if there is one.
+    fn progress(&self, progress: Progress) {
+        if let Some(callback) = self.progress {
+            callback(progress);
+        }
+    }
+
+    /// Prints `message`, or passes it to the progress callback if there is one.
+    fn status(&self, message: String) {
+        match self.progress {
+            Some(callback) => callback(Progress::Message(message)),
+            None => println!("{message}"),
+        }
+    }
+
+    /// Records a problem that doesn't stop the run, printing it unless running strictly.
+    fn warn(&self, message: String) {
+        if self.strict != Some(true) {
+            status!(self, "Warning: {message}");
+        }
+        self.warnings
+            .lock()
+            .expect("Warnings lock poisoned")
+            .push(message);
+    }
+
+    /// When running strictly, fails if any warnings have been raised.
+    fn check_warnings(&self) -> Result<(), HdtrError> {
+        let warnings = self.warnings.lock().expect("Warnings lock poisoned");
+        if self.strict == Some(true) && !warnings.is_empty() {
+            return Err(HdtrError::PipelineError(
+                format!("Stopped by strict mode: {}", warnings.join("; ")).into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn match_exposure(&self, images: &mut InputImages) {
+        if self.match_exposure != Some(true) {
+            return;
+        }
+
+        let s = std::time::Instant::now();
+        let factors = images.match_exposure();
+        for (image, factor) in images.images.iter().zip(factors) {
+            status!(self, "Exposure of {}: x{factor:.3}", image.path.display());
+            if (factor - 1.).abs() > EXPOSURE_DRIFT_THRESHOLD {
+                self.warn(format!(
+                    "Exposure of {} drifted from the median by a factor of {factor:.3}",
+                    image.path.display()
+                ));
+            }
+        }
+        status!(
+            self,
+            "Matched exposure of {} images in {:?}",
+            images.len(),
+            s.elapsed()
+        );
+    }
+
+    fn align(&self, images: &mut InputImages) {
+        if self.align != Some(true) {
+            return;
+        }
+
+        let s = std::time::Instant::now();
+        let offsets = images.align(ALIGN_SEARCH_RADIUS);
+        for (image, (dx, dy)) in images.images.iter().zip(offsets).skip(1) {
+            status!(self, "Offset of {}: ({dx}, {dy})", image.path.display());
+        }
+        status!(self, "Aligned {} images in {:?}", images.len(), s.elapsed());
+    }
+
+    /// Records the input whose EXIF `preserve_exif` copies.
+    fn set_exif_source(&self, first: Option<&InputImage>) {
+        *self.exif_source.lock().expect("EXIF source lock poisoned") =
+            first.map(|image| image.path.clone());
+    }
+
+    /// Saves the blended `canvas` of `images` to `destination`.
+    fn write_output(
+        &self,
+        canvas: &RgbImage,
+        destination: &Path,
+        images: &InputImages,
+    ) -> Result<(), HdtrError> {
+        let mut canvas = Cow::Borrowed(canvas);
+        if let Some(gamma) = self.output_gamma {
+            apply_gamma(canvas.to_mut(), gamma);
+        }
+
+        if let Some(lut) = &self.lut {
+            Lut::from_cube(lut)?.apply(canvas.to_mut());
+        }
+
+        let format = self.output_format()?;
+        let exif = match self.preserve_exif {
+            Some(true) if !metadata::supports_exif(format) => {
+                self.warn(format!("EXIF can't be saved in {format:?} files"));
+                None
+            }
+            Some(true) => {
+                let source = self.exif_source.lock().expect("EXIF source lock poisoned");
+                let source = source.as_deref().unwrap_or(&images.images[0].path);
+                let exif = metadata::read_exif(source)?;
+                if exif.is_none() {
+                    self.warn(format!("No EXIF found in {}", source.display()));
+                }
+                exif
+            }
+            _ => None,
+        };
+
+        let profile = match &self.color_profile {
+            Some(name) => Some(ColorProfile::load(name)?).filter(|profile| {
+                let supported = profile.supported_by(format);
+                if !supported {
+                    self.warn(format!("{name} can't be embedded in {format:?} files"));
+                }
+                supported
+            }),
+            None => None,
+        };
+
+        self.check_warnings()?;
+
+        // Converting the finished blend, rather than the inputs, keeps the luminance weighted by
+        // the blended colors.
+        match (self.grayscale, format) {
+            // WebP has no single-channel mode, so the luminance is stored in all three channels.
+            (Some(true), ImageFormat::WebP) => {
+                let gray = DynamicImage::ImageLuma8(imageops::grayscale(&*canvas));
+                self.write_canvas(&gray.to_rgb8(), destination, format)?
+            }
+            (Some(true), _) => {
+                self.write_canvas(&imageops::grayscale(&*canvas), destination, format)?
+            }
+            _ => self.write_canvas(&*canvas, destination, format)?,
+        }
+
+        if let Some(tiff) = exif {
+            metadata::write_exif(destination, format, &tiff)?;
+        }
+
+        if let Some(profile) = profile {
+            profile.embed(destination, format)?;
+        }
+
+        self.record_report(images, destination, canvas.dimensions())
+    }
+
+    /// Writes a 16-bit blend to `destination`, keeping its full depth if the output is a PNG or
+    /// TIFF and no option needs 8-bit pixels. Otherwise it's rounded to 8 bits and written by
+    /// `write_output`.
+    fn write_output16(
+        &self,
+        canvas: &Rgb16Image,
+        destination: &Path,
+        images: &InputImages,
+    ) -> Result<(), HdtrError> {
+        let format = self.output_format()?;
+        let keeps_depth = matches!(format, ImageFormat::Png | ImageFormat::Tiff)
+            && self.output_gamma.is_none()
+            && self.lut.is_none()
+            && self.grayscale != Some(true)
+            && self.png_compression.is_none()
+            && self.preserve_exif != Some(true)
+            && self.color_profile.is_none();
+        if !keeps_depth {
+            let canvas = DynamicImage::ImageRgb16(canvas.clone()).to_rgb8();
+            return self.write_output(&canvas, destination, images);
+        }
+
+        self.check_warnings()?;
+        canvas.save_with_format(destination, format)?;
+        self.record_report(images, destination, canvas.dimensions())
+    }
+
+    /// Records the report of a run whose `(width, height)` output was written to `destination`.
+    fn record_report(
+        &self,
+        images: &InputImages,
+        destination: &Path,
+        (width, height): (u32, u32),
+    ) -> Result<(), HdtrError> {
+        let generate_masks = match self.masks_skipped.load(Ordering::Relaxed) {
+            true => None,
+            false => self.generate_masks.as_ref(),
+        };
+        let report = PipelineReport {
+            inputs: images
+                .iter()
+                .map(|input| ReportedImage {
+                    path: input.path.clone(),
+                    width: input.im.width(),
+                    height: input.im.height(),
+                })
+                .collect(),
+            blend_mode: self.blend_mode.unwrap_or_default(),
+            mask_type: match generate_masks {
+                Some(MaskSpec::Uniform(mask_type)) => Some(*mask_type),
+                _ => None,
+            },
+            mask_types: generate_masks.map_or(Vec::new(), |spec| spec.mask_types().to_vec()),
+            normalized: self.normalize_masks == Some(true),
+            output: ReportedImage {
+                path: destination.to_path_buf(),
+                width,
+                height,
+            },
+            output_bytes: std::fs::metadata(destination)?.len(),
+        };
+        *self.report.lock().expect("Report lock poisoned") = Some(report);
+
+        Ok(())
+    }
+
+    fn execute_streaming(&self, spec: &MaskSpec, preview: Option<u32>) -> Result<(), HdtrError> {
+        let s = std::time::Instant::now();
+        let done = AtomicUsize::new(0);
+        let loaded = self
+            .filenames
+            .par_iter()
+            .enumerate()
+            .map(|(idx, filename)| {
+                let imgs = filename.load_images().inspect(|imgs| {
+                    for img in imgs {
+                        self.log_loaded(img, None);
+                    }
+                });
+                self.report_loaded(&done);
+                (idx, filename, imgs)
+            })
+            .collect::<Vec<_>>();
+        let mut loaded = self.drop_failed_loads(loaded)?;
+
+        loaded.sort_by_key(|(idx, _)| *idx);
+        let mut images = loaded
+            .into_iter()
+            .flat_map(|(_, imgs)| imgs)
+            .collect::<Vec<_>>();
+        self.sort_inputs(&mut images, |image| image);
+        for image in &images {
+            image.ensure_nonempty()?;
+        }
+        self.set_exif_source(images.first());
+
+        // Blending is a per-pixel sum, so reversing the images is equivalent to reversing the
+        // masks they're paired with.
+        if self.reverse == Some(true) {
+            images.reverse();
+        }
+
+        status!(self, "Loaded {} images in {:?}", images.len(), s.elapsed());
+        self.record_timing(|t| &mut t.load_ms, s.elapsed());
+
+        let expected = images
+            .first()
+            .ok_or(HdtrError::HDTR("No images were loaded".into()))?
+            .im
+            .dimensions();
+
+        if let Some(img) = images.iter().find(|img| img.im.dimensions() != expected) {
+            return Err(HdtrError::DimensionMismatch {
+                expected,
+                received: img.im.dimensions(),
+                details: "Image has different dimensions than expected".into(),
+            });
+        }
+
+        let expected = match self.region {
+            Some(region) => {
+                let [x, y, w, h] = check_region(region, expected)?;
+                images
+                    .par_iter_mut()
+                    .for_each(|img| img.im = img.im.crop_imm(x, y, w, h));
+                (w, h)
+            }
+            None => expected,
+        };
+
+        let (width, height) = match preview {
+            Some(max_dim) => {
+                let dims = preview_dimensions(expected, max_dim);
+                downscale(&mut images, dims);
+                dims
+            }
+            None => expected,
+        };
+
+        // Masks are computed while blending, so none are stored.
+        let mut images = InputImages {
+            images,
+            masks: Vec::new(),
+            width,
+            height,
+        };
+
+        self.match_exposure(&mut images);
+        self.align(&mut images);
+
+        let destination = self.destination(preview)?;
+        let s = std::time::Instant::now();
+        self.check_per_image_counts(images.images.len())?;
+        let mask_types =
+            self.band_mask_types(spec, images.images.len(), (images.width, images.height));
+        self.log_bands(&images.images, 0..images.images.len(), &mask_types);
+        self.progress(Progress::Stage("Compositing"));
+        let canvas = images.blend_band_masks_streaming(
+            &mask_types,
+            self.normalize_masks == Some(true),
+            self.antialias == Some(true),
+        );
+        self.write_output(&canvas, &destination, &images)?;
+        status!(self, "Saved {} in {:?}", destination.display(), s.elapsed());
+        self.record_timing(|t| &mut t.save_ms, s.elapsed());
+
+        Ok(())
+    }
+}
+
+/// Constructs a `Pipeline` in code. Options that aren't set keep their defaults.
+#[derive(Default)]
+pub struct PipelineBuilder {
+    pipeline: Pipeline,
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_image<S: Into<String>>(mut self, path: S) -> Self {
+        self.pipeline.filenames.push(path.into().into());
+        self
+    }
+
+    pub fn add_image_with_mask<S: Into<String>, M: Into<String>>(
+        mut self,
+        path: S,
+        mask: M,
+    ) -> Self {
+        self.pipeline.filenames.push(PipelineInputImage {
+            image: path.into(),
+            mask: Some(mask.into()),
+        });
+        self
+    }
+
+    pub fn generate(mut self, mask_type: MaskType) -> Self {
+        self.pipeline.generate_masks = Some(mask_type.into());
+        self
+    }
+
+    /// Generates the `i`th image's mask with `mask_types[i]`.
+    pub fn generate_per_image(mut self, mask_types: Vec<MaskType>) -> Self {
+        self.pipeline.generate_masks = Some(MaskSpec::PerImage(mask_types));
+        self
+    }
+
+    pub fn normalize(mut self, normalize: bool) -> Self {
+        self.pipeline.normalize_masks = Some(normalize);
+        self
+    }
+
+    pub fn save_masks(mut self, save_masks: bool) -> Self {
+        self.pipeline.save_masks = Some(save_masks);
+        self
+    }
+
+    pub fn output<S: Into<String>>(mut self, path: S) -> Self {
+        self.pipeline.save = path.into();
+        self
+    }
+
+    /// Returns the pipeline, provided it has at least one image and an output path.
+    pub fn build(self) -> Result<Pipeline, HdtrError> {
+        if self.pipeline.filenames.is_empty() {
+            Err(HdtrError::NoInputFilesSpecified)
+        } else if self.pipeline.save.is_empty() {
+            Err(HdtrError::NoSaveOperationSpecified)
+        } else {
+            Ok(self.pipeline)
+        }
+    }
+}
+
+#[test]
+fn test_builder() {
+    assert!(matches!(
+        Pipeline::builder().output("out.png").build(),
+        Err(HdtrError::NoInputFilesSpecified)
+    ));
+    assert!(matches!(
+        Pipeline::builder().add_image("a.png").build(),
+        Err(HdtrError::NoSaveOperationSpecified)
+    ));
+
+    let pipeline = Pipeline::builder()
+        .add_image("a.png")
+        .add_image_with_mask("b.png", "b_mask.png")
+        .generate(MaskType::VerticalFlat)
+        .normalize(true)
+        .output("out.png")
+        .build()
+        .unwrap();
+    assert_eq!(pipeline.filenames.len(), 2);
+    assert_eq!(pipeline.filenames[1].mask.as_deref(), Some("b_mask.png"));
+    assert_eq!(pipeline.normalize_masks, Some(true));
+}
+
+#[test]
+fn test_yaml_round_trip() {
+    let yaml = Pipeline::example(None).to_yaml().unwrap();
+    assert!(yaml.contains("VerticalLogistic:"));
+
+    let pipeline = Pipeline::from_yaml(&yaml).unwrap();
+    assert_eq!(pipeline.filenames.len(), 4);
+    assert!(matches!(
+        pipeline.generate_masks,
+        Some(MaskSpec::Uniform(MaskType::VerticalLogistic { k })) if k == 0.01
+    ));
+
+    let yaml = "filenames: [{image: a.png}]\ngenerate_masks: {VerticalLogistic: {k: 0.02}}\nsave: out.png\n";
+    let pipeline = Pipeline::from_yaml(yaml).unwrap();
+    assert!(matches!(
+        pipeline.generate_masks,
+        Some(MaskSpec::Uniform(MaskType::VerticalLogistic { k })) if k == 0.02
+    ));
+}
+
+#[test]
+fn test_save_example() {
+    Pipeline::save_example("test_pipeline.json", None).unwrap();
+}
+
+#[test]
+fn test_toml_round_trip() {
+    let toml = toml::to_string_pretty(&Pipeline::example(None)).unwrap();
+    assert!(toml.contains("[generate_masks.VerticalLogistic]"));
+
+    let pipeline = toml::from_str::<Pipeline>(&toml).unwrap();
+    assert_eq!(pipeline.filenames.len(), 4);
+    assert!(matches!(
+        pipeline.generate_masks,
+        Some(MaskSpec::Uniform(MaskType::VerticalLogistic { k })) if k == 0.01
+    ));
+}
+
+#[test]
+fn test_mask_ops() {
+    let json = r#"{
+        "filenames": [],
+        "generate_masks": "VerticalFlat",
+        "mask_ops": ["Generate", {"Blur": 5.0}, "Invert", "Normalize"],
+        "save": "out.png"
+    }"#;
+    let mut pipeline = serde_json::from_str::<Pipeline>(json).unwrap();
+    assert!(matches!(
+        pipeline.mask_ops()[..],
+        [
+            MaskOp::Generate,
+            MaskOp::Blur(sigma),
+            MaskOp::Invert,
+            MaskOp::Normalize
+        ] if sigma == 5.
+    ));
+
+    // Without mask_ops, the individual options apply in their fixed order.
+    pipeline.mask_ops = None;
+    pipeline.normalize_masks = Some(true);
+    pipeline.reverse = Some(true);
+    assert!(matches!(
+        pipeline.mask_ops()[..],
+        [MaskOp::Generate, MaskOp::Reverse, MaskOp::Normalize]
+    ));
+}
+
+#[test]
+fn test_reverse_ignores_mask_files() {
+    let dir = std::env::temp_dir();
+    let mut builder = Pipeline::builder();
+    for (i, (color, weight)) in [([250u8, 0, 0], 255u8), ([0, 0, 250], 0)]
+        .into_iter()
+        .enumerate()
+    {
+        let input = dir.join(format!("hdtr_test_reverse_input{i}.png"));
+        let mask = dir.join(format!("hdtr_test_reverse_mask{i}.png"));
+        image::RgbImage::from_pixel(4, 2, image::Rgb(color))
+            .save(&input)
+            .unwrap();
+        image::GrayImage::from_pixel(4, 2, image::Luma([weight]))
+            .save(&mask)
+            .unwrap();
+        builder = builder.add_image_with_mask(input.to_str().unwrap(), mask.to_str().unwrap());
+    }
+
+    let output = dir.join("hdtr_test_reverse_output.png");
+    let mut pipeline = builder.output(output.to_str().unwrap()).build().unwrap();
+    pipeline.reverse = Some(true);
+    assert!(!pipeline
+        .mask_ops()
+        .iter()
+        .any(|op| matches!(op, MaskOp::Reverse)));
+
+    // Each image keeps its own mask, so only the first shows.
+    pipeline.execute().unwrap();
+    let output = image::open(&output).unwrap().to_rgb8();
+    assert!(output.pixels().all(|p| p.0 == [250, 0, 0]));
+}
+
+#[test]
+fn test_alpha_masks_skip_missing_masks() {
+    let dir = std::env::temp_dir();
+    let red = dir.join("hdtr_test_alpha_red.png");
+    let blue = dir.join("hdtr_test_alpha_blue.png");
+    let mask = dir.join("hdtr_test_alpha_mask.png");
+    image::RgbImage::from_pixel(4, 2, image::Rgb([250, 0, 0]))
+        .save(&red)
+        .unwrap();
+    image::RgbImage::from_pixel(4, 2, image::Rgb([0, 0, 250]))
+        .save(&blue)
+        .unwrap();
+    image::RgbaImage::from_pixel(4, 2, image::Rgba([0, 0, 0, 255]))
+        .save(&mask)
+        .unwrap();
+
+    let output = dir.join("hdtr_test_alpha_output.png");
+    let mut pipeline = Pipeline::builder()
+        .add_image_with_mask(red.to_str().unwrap(), mask.to_str().unwrap())
+        .add_image(blue.to_str().unwrap())
+        .normalize(true)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+    pipeline.mask_source = Some(MaskChannel::Alpha);
+    pipeline.execute().unwrap();
+
+    // The image without a mask file has no weight, rather than an opaque mask.
+    let output = image::open(&output).unwrap().to_rgb8();
+    assert!(output.pixels().all(|p| p.0 == [250, 0, 0]));
+}
+
+#[test]
+fn test_single_image_is_copied() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("hdtr_test_single_input.png");
+    let output = dir.join("hdtr_test_single_output.png");
+    // 16 bits, to check that the copy doesn't round them away.
+    let source = image::ImageBuffer::<image::Rgb<u16>, _>::from_fn(7, 5, |x, y| {
+        image::Rgb([x as u16 * 9001, y as u16 * 257 + 1, 999])
+    });
+    source.save(&input).unwrap();
+
+    let mut pipeline = Pipeline::builder()
+        .add_image(input.to_str().unwrap())
+        .generate(MaskType::VerticalLogistic { k: 0.01 })
+        .normalize(true)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+    let outcome = pipeline.execute().unwrap();
+
+    assert_eq!(image::open(&output).unwrap().to_rgb16(), source);
+    let report = outcome.report.unwrap();
+    assert_eq!((report.output.width, report.output.height), (7, 5));
+    // No mask was generated, so none is reported.
+    assert!(report.mask_type.is_none() && report.mask_types.is_empty());
+    assert!(outcome.timings.load_ms.is_some() && outcome.timings.save_ms.is_some());
+
+    // Saving the mask needs it prepared, so the mask work isn't skipped.
+    pipeline.save_masks = Some(true);
+    pipeline.mask_output_dir = Some(dir.join("hdtr_test_single_masks").display().to_string());
+    let outcome = pipeline.execute().unwrap();
+    assert_eq!(outcome.saved_masks.len(), 1);
+    assert!(outcome.saved_masks[0].exists());
+    let report = outcome.report.unwrap();
+    assert!(matches!(
+        (report.mask_type, &report.mask_types[..]),
+        (
+            Some(MaskType::VerticalLogistic { .. }),
+            [MaskType::VerticalLogistic { .. }]
+        )
+    ));
+}
+
+#[test]
+fn test_execute_with_progress() {
+    let input = std::env::temp_dir().join("hdtr_test_progress_input.png");
+    image::RgbImage::from_pixel(4, 4, image::Rgb([9, 9, 9]))
+        .save(&input)
+        .unwrap();
+    let pipeline = Pipeline::builder()
+        .add_image(input.to_str().unwrap())
+        .add_image(input.to_str().unwrap())
+        .generate(MaskType::VerticalFlat)
+        .output(
+            std::env::temp_dir()
+                .join("hdtr_test_progress_output.png")
+                .to_str()
+                .unwrap(),
+        )
+        .build()
+        .unwrap();
+
+    let events = Mutex::new(Vec::new());
+    pipeline
+        .clone()
+        .execute_with_progress(|progress| events.lock().unwrap().push(progress))
+        .unwrap();
+
+    let events = events.into_inner().unwrap();
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Progress::Loaded { done: 2, total: 2 })));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Progress::Stage("Compositing"))));
+    assert!(events.iter().any(|e| matches!(e, Progress::Message(_))));
+}
+
+#[test]
+fn test_grayscale_output() {
+    let dir = std::env::temp_dir();
+    let mut builder = Pipeline::builder();
+    for (i, color) in [[200u8, 40, 10], [10, 90, 250]].into_iter().enumerate() {
+        let input = dir.join(format!("hdtr_test_gray_input{i}.png"));
+        image::RgbImage::from_pixel(6, 3, image::Rgb(color))
+            .save(&input)
+            .unwrap();
+        builder = builder.add_image(input.to_str().unwrap());
+    }
+
+    let color = dir.join("hdtr_test_gray_color.png");
+    let gray = dir.join("hdtr_test_gray_gray.png");
+    let mut pipeline = builder
+        .generate(MaskType::VerticalLogistic { k: 0.5 })
+        .normalize(true)
+        .output(color.to_str().unwrap())
+        .build()
+        .unwrap();
+    pipeline.execute().unwrap();
+    pipeline.save = gray.to_str().unwrap().into();
+    pipeline.grayscale = Some(true);
+    pipeline.execute().unwrap();
+
+    let color = image::open(&color).unwrap().to_rgb8();
+    let gray = image::open(&gray).unwrap();
+    assert_eq!(gray.color(), image::ColorType::L8);
+    for (c, g) in color.pixels().zip(gray.to_luma8().pixels()) {
+        let luminance = 0.2126 * c[0] as f64 + 0.7152 * c[1] as f64 + 0.0722 * c[2] as f64;
+        assert!((g[0] as f64 - luminance).abs() <= 1.);
+    }
+}
+
+#[test]
+fn test_bands() {
+    let dir = std::env::temp_dir();
+    let mut builder = Pipeline::builder();
+    for (i, color) in [[250u8, 0, 0], [0, 0, 250]].into_iter().enumerate() {
+        let input = dir.join(format!("hdtr_test_bands_input{i}.png"));
+        image::RgbImage::from_pixel(6, 3, image::Rgb(color))
+            .save(&input)
+            .unwrap();
+        builder = builder.add_image(input.to_str().unwrap());
+    }
+
+    let output = dir.join("hdtr_test_bands_output.png");
+    let mut pipeline = builder
+        .normalize(true)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+    pipeline.bands = Some(
+        serde_json::from_str(r#"[{"image": 0, "x0": 0, "x1": 4}, {"image": 1, "x0": 2, "x1": 6}]"#)
+            .unwrap(),
+    );
+    pipeline.execute().unwrap();
+
+    // The bands overlap in columns 2 and 3, which normalization splits evenly.
+    let output = image::open(&output).unwrap().to_rgb8();
+    assert_eq!(output.get_pixel(1, 1).0, [250, 0, 0]);
+    assert_eq!(output.get_pixel(3, 1).0, [124, 0, 124]);
+    assert_eq!(output.get_pixel(4, 1).0, [0, 0, 250]);
+
+    pipeline.bands = Some(vec![BandSpec {
+        image: 0,
+        x0: 3,
+        x1: 3,
+        y0: None,
+        y1: None,
+    }]);
+    assert!(pipeline.validate().is_err());
+}
+
+#[test]
+fn test_mask_cache() {
+    let dir = std::env::temp_dir();
+    let cache = dir.join("hdtr_test_mask_cache");
+    let _ = std::fs::remove_dir_all(&cache);
+
+    let mut builder = Pipeline::builder();
+    for i in 0..3u8 {
+        let input = dir.join(format!("hdtr_test_cache_input{i}.png"));
+        image::RgbImage::from_pixel(8, 2, image::Rgb([i * 80; 3]))
+            .save(&input)
+            .unwrap();
+        builder = builder.add_image(input.to_str().unwrap());
+    }
+    let output = dir.join("hdtr_test_cache_output.png");
+    let mut pipeline = builder
+        .generate(MaskType::VerticalLogistic { k: 0.05 })
+        .normalize(true)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+    pipeline.mask_cache_dir = Some(cache.to_str().unwrap().into());
+
+    pipeline.execute().unwrap();
+    let uncached = image::open(&output).unwrap();
+    pipeline.execute().unwrap();
+    assert_eq!(image::open(&output).unwrap(), uncached);
+    assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 1);
+
+    // A different mask type is cached separately rather than reusing the first masks.
+    pipeline.generate_masks = Some(MaskType::VerticalLogistic { k: 0.5 }.into());
+    pipeline.execute().unwrap();
+    assert_eq!(std::fs::read_dir(&cache).unwrap().count(), 2);
+    assert_ne!(image::open(&output).unwrap(), uncached);
+}
+
+#[test]
+fn test_resolved() {
+    let mut pipeline = Pipeline::builder()
+        .add_image("a.png")
+        .add_image("b.png")
+        .generate(MaskType::VerticalFlat)
+        .normalize(true)
+        .output("out.jpg")
+        .build()
+        .unwrap();
+    pipeline.blur_masks = Some(2.);
+
+    let resolved = pipeline.resolved();
+    assert!(matches!(resolved.blend_mode, BlendMode::Mask));
+    assert_eq!(resolved.precision, Precision::Double);
+    assert_eq!(resolved.format, "jpg");
+    assert_eq!(resolved.quality, Some(DEFAULT_JPEG_QUALITY));
+    assert_eq!(resolved.mask_suffix, DEFAULT_MASK_SUFFIX);
+    assert_eq!(resolved.output_gamma, [1.; 3]);
+    assert!(!resolved.strict);
+    assert!(matches!(
+        resolved.mask_ops[..],
+        [MaskOp::Generate, MaskOp::Blur(_), MaskOp::Normalize]
+    ));
+
+    // Everything is concrete, so it round-trips through JSON without nulls for defaulted fields.
+    let json = serde_json::to_value(&resolved).unwrap();
+    assert_eq!(json["png_compression"], "Default");
+    assert_eq!(json["stream"], false);
+
+    // The deprecated luminance_masks is reported as the mask_source it stands for.
+    assert_eq!(resolved.mask_source, None);
+    pipeline.luminance_masks = Some(true);
+    assert_eq!(
+        pipeline.resolved().mask_source,
+        Some(MaskChannel::Luminance)
+    );
+
+    // WebP is lossless unless a quality is given.
+    pipeline.save = "out.webp".into();
+    assert_eq!(pipeline.resolved().quality, None);
+}
+
+#[test]
+fn test_per_image_mask_types() {
+    let json = r#"{
+        "filenames": [{"image": "a.png"}, {"image": "b.png"}, {"image": "c.png"}],
+        "generate_masks": [{"VerticalLogistic": {"k": 0.1}}, "VerticalFlat", "RadialFlat"],
+        "ks": [0.5, 0.6, 0.7],
+        "save": "out.png"
+    }"#;
+    let mut pipeline = serde_json::from_str::<Pipeline>(json).unwrap();
+    let Some(spec) = &pipeline.generate_masks else {
+        panic!("generate_masks should be set");
+    };
+    assert!(matches!(spec, MaskSpec::PerImage(types) if types.len() == 3));
+
+    // Each image keeps its own type, with only the logistic one taking its k.
+    assert!(matches!(
+        Run::new(&pipeline).band_mask_types(spec, 3, (30, 10))[..],
+        [
+            MaskType::VerticalLogistic { k },
+            MaskType::VerticalFlat,
+            MaskType::RadialFlat
+        ] if k == 0.5
+    ));
+
+    pipeline.ks = None;
+    assert!(matches!(
+        pipeline.check_per_image_counts(2),
+        Err(HdtrError::PipelineError(e)) if e.contains("generate_masks has 3 entries")
+    ));
+}
+
+#[test]
+fn test_color_profile() {
+    let dir = std::env::temp_dir();
+    let input = dir.join("hdtr_test_profile_input.png");
+    image::RgbImage::from_pixel(4, 4, image::Rgb([30, 60, 90]))
+        .save(&input)
+        .unwrap();
+    let icc = dir.join("hdtr_test_profile.icc");
+    std::fs::write(&icc, [7u8; 300]).unwrap();
+
+    let output = dir.join("hdtr_test_profile_output.png");
+    let mut pipeline = Pipeline::builder()
+        .add_image(input.to_str().unwrap())
+        .add_image(input.to_str().unwrap())
+        .generate(MaskType::VerticalFlat)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+
+    for (profile, chunk) in [("sRGB", b"sRGB"), (icc.to_str().unwrap(), b"iCCP")] {
+        pipeline.color_profile = Some(profile.into());
+        pipeline.execute().unwrap();
+        let bytes = std::fs::read(&output).unwrap();
+        assert!(bytes.windows(4).any(|w| w == chunk));
+        assert_eq!(
+            image::open(&output).unwrap().to_rgb8().get_pixel(0, 0).0,
+            [30, 60, 90]
+        );
+    }
+
+    // Formats that can't hold the profile are written without it, with a warning.
+    pipeline.color_profile = Some("sRGB".into());
+    pipeline.save = dir
+        .join("hdtr_test_profile_output.bmp")
+        .to_str()
+        .unwrap()
+        .into();
+    let outcome = pipeline.execute().unwrap();
+    assert_eq!(outcome.warnings.len(), 1);
+    pipeline.strict = Some(true);
+    assert!(pipeline.execute().is_err());
+
+    pipeline.color_profile = Some(dir.join("hdtr_test_missing.icc").to_str().unwrap().into());
+    assert!(pipeline.validate().is_err());
+}
+
+/// Names the subdirectory of `mask_cache_dir` holding masks generated from `mask_types`, one per
+/// image, at `dimensions`. Any change to these gives a different name, so stale masks are never
+/// loaded.
+fn mask_cache_key(mask_types: &[MaskType], antialias: bool, dimensions: (u32, u32)) -> String {
+    let key = serde_json::to_string(&(mask_types, antialias, dimensions))
+        .expect("Mask types serialize to JSON");
+    format!("{:08x}", crc32fast::hash(key.as_bytes()))
+}
+
+/// The path of the `index`th mask cached in `dir`.
+fn cached_mask_path(dir: &Path, index: usize) -> PathBuf {
+    dir.join(format!("mask_{index:03}.png"))
+}
+
+/// Loads the masks cached in `dir` for `mask_types`, or `None` if any is missing or unreadable.
+fn read_cached_masks(dir: &Path, mask_types: &[MaskType]) -> Option<Vec<DynamicImage>> {
+    (0..mask_types.len())
+        .into_par_iter()
+        .map(|i| image::open(cached_mask_path(dir, i)).ok())
+        .collect()
+}
+
+/// Caches `masks` in `dir`, creating it if necessary.
+fn write_cached_masks(dir: &Path, masks: &[DynamicImage]) -> Result<(), HdtrError> {
+    std::fs::create_dir_all(dir)?;
+    masks.par_iter().enumerate().try_for_each(|(i, mask)| {
+        let path = cached_mask_path(dir, i);
+        mask.save_with_format(&path, ImageFormat::Png)
+            .map_err(|_| HdtrError::ErrorWritingFile(path))
+    })
+}
+
+/// Scales `(width, height)` so that the longer edge is at most `max_dim`, keeping the aspect ratio.
+fn preview_dimensions((width, height): (u32, u32), max_dim: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    if longest <= max_dim {
+        return (width, height);
+    }
+
+    let scale = max_dim as f64 / longest as f64;
+    let scaled = |d: u32| ((d as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+fn downscale(images: &mut [InputImage], (width, height): (u32, u32)) {
+    images
+        .par_iter_mut()
+        .for_each(|img| img.im = img.im.resize_exact(width, height, FilterType::Triangle));
+}
+
+fn default_mask((width, height): (u32, u32)) -> DynamicImage {
+    DynamicImage::new_luma8(width, height)
+}
+
+#[test]
+fn test_preview_destination() {
+    let mut pipeline = Pipeline {
+        save: "out/result.png".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(
+        pipeline.destination(Some(2)).unwrap(),
+        Path::new("out/preview_result.png")
+    );
+    assert_eq!(
+        pipeline.destination(None).unwrap(),
+        Path::new("out/result.png")
+    );
+
+    // Without a file name there's nothing to prefix, and reusing the path would overwrite it.
+    pipeline.save = "out/..".to_string();
+    assert!(pipeline.destination(Some(2)).is_err());
+}
+
+#[test]
+fn test_ks_needs_k() {
+    let input = std::env::temp_dir().join("hdtr_test_ks_input.png");
+    image::RgbImage::new(4, 4).save(&input).unwrap();
+    let mut pipeline = Pipeline::builder()
+        .add_image(input.to_str().unwrap())
+        .add_image(input.to_str().unwrap())
+        .generate_per_image(vec![
+            MaskType::VerticalLogistic { k: 0.1 },
+            MaskType::Grid { cols: 2, rows: 2 },
+        ])
+        .output("hdtr_test_ks_output.png")
+        .build()
+        .unwrap();
+    pipeline.ks = Some(vec![0.2, 0.3]);
+    assert!(pipeline.validate().is_err());
+
+    pipeline.generate_masks = Some(MaskType::Cross { k: 0.1 }.into());
+    assert!(pipeline.validate().is_ok());
+}
+
+#[test]
+fn test_exif_comes_from_first_input_when_streaming_reversed() {
+    let dir = std::env::temp_dir();
+    let inputs = ["2020:01:01 00:00:00", "2021:01:01 00:00:00"].map(|time| {
+        let path = dir.join(format!("hdtr_test_exif_{}.png", &time[..4]));
+        image::RgbImage::new(4, 4).save(&path).unwrap();
+
+        let field = exif::Field {
+            tag: exif::Tag::DateTimeOriginal,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Ascii(vec![time.as_bytes().to_vec()]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&field);
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        metadata::write_exif(&path, ImageFormat::Png, tiff.get_ref()).unwrap();
+        path
+    });
+
+    let output = dir.join("hdtr_test_exif_output.png");
+    let mut pipeline = Pipeline::builder()
+        .add_image(inputs[0].to_str().unwrap())
+        .add_image(inputs[1].to_str().unwrap())
+        .generate(MaskType::VerticalFlat)
+        .output(output.to_str().unwrap())
+        .build()
+        .unwrap();
+    pipeline.stream = Some(true);
+    pipeline.reverse = Some(true);
+    pipeline.preserve_exif = Some(true);
+    pipeline.execute().unwrap();
+
+    assert_eq!(
+        metadata::capture_time(&output).as_deref(),
+        Some("2020-01-01 00:00:00")
+    );
+}
+
+#[test]
+fn test_skip_unreadable() {
+    let dir = std::env::temp_dir();
+    let good = dir.join("hdtr_test_skip_good.png");
+    image::RgbImage::from_pixel(4, 4, image::Rgb([50, 60, 70]))
+        .save(&good)
+        .unwrap();
+    let bad = dir.join("hdtr_test_skip_bad.png");
+    std::fs::write(&bad, b"not an image").unwrap();
+
+    let mut pipeline = Pipeline::builder()
+        .add_image(good.to_str().unwrap())
+        .add_image(bad.to_str().unwrap())
+        .add_image(good.to_str().unwrap())
+        .generate(MaskType::VerticalFlat)
+        .output(dir.join("hdtr_test_skip_output.png").to_str().unwrap())
+        .build()
+        .unwrap();
+    assert!(pipeline.execute().is_err());
+
+    pipeline.skip_unreadable = Some(true);
+    let outcome = pipeline.execute().unwrap();
+    assert_eq!(outcome.report.unwrap().inputs.len(), 2);
+    assert_eq!(outcome.warnings.len(), 1);
+    assert!(outcome.warnings[0].contains("hdtr_test_skip_bad.png"));
+
+    // Skipping every input leaves nothing to blend.
+    pipeline.filenames.retain(|f| f.image.contains("bad"));
+    assert!(pipeline.execute().is_err());
+}
+
+#[test]
+fn test_seam_width_sets_k() {
+    let pipeline = Pipeline {
+        seam_width: Some(60.),
+        ..Default::default()
+    };
+    let spec = MaskSpec::PerImage(vec![
+        MaskType::VerticalLogistic { k: 1. },
+        MaskType::HorizontalLogistic { k: 1. },
+    ]);
+
+    // Vertical bands split the width and horizontal bands the height.
+    let mask_types = Run::new(&pipeline).band_mask_types(&spec, 2, (400, 100));
+    assert!(matches!(
+        mask_types[..],
+        [MaskType::VerticalLogistic { k: k_x }, MaskType::HorizontalLogistic { k: k_y }]
+            if k_x == k_for_seam_width(60., 200.) && k_y == k_for_seam_width(60., 50.)
+    ));
+}
+
+#[test]
+fn test_softmax_temperature_needs_normalize() {
+    let input = std::env::temp_dir().join("hdtr_test_softmax_input.png");
+    image::RgbImage::new(4, 4).save(&input).unwrap();
+    let mut pipeline = Pipeline::builder()
+        .add_image(input.to_str().unwrap())
+        .add_image(input.to_str().unwrap())
+        .generate(MaskType::VerticalLogistic { k: 0.1 })
+        .output("hdtr_test_softmax_output.png")
+        .build()
+        .unwrap();
+    pipeline.softmax_temperature = Some(0.5);
+    assert!(pipeline.validate().is_err());
+
+    pipeline.normalize_masks = Some(true);
+    assert!(pipeline.validate().is_ok());
+
+    // Streaming always normalizes linearly.
+    pipeline.stream = Some(true);
+    assert!(pipeline.validate().is_err());
+
+    // Nor does it honor Single precision.
+    pipeline.softmax_temperature = None;
+    assert!(pipeline.validate().is_ok());
+    pipeline.precision = Some(Precision::Single);
+    assert!(pipeline.validate().is_err());
 }